@@ -1,6 +1,10 @@
 use clap::Parser;
 use log::LevelFilter;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::LazyLock;
+use tempfile::env::temp_dir;
 
 #[derive(Parser)]
 #[command(version, about, author)]
@@ -9,9 +13,10 @@ pub(crate) struct Args {
         short,
         long,
         env,
+        required_unless_present = "eval_worker",
         help = "The authentication token for logging into the Discord bot account."
     )]
-    pub(crate) token: String,
+    pub(crate) token: Option<String>,
     #[clap(
         short,
         long,
@@ -44,6 +49,187 @@ pub(crate) struct Args {
         help = "Clone depth for the nixpkgs repo."
     )]
     pub(crate) clone_depth: i32,
+    #[clap(
+        long,
+        env,
+        help = "Directory to keep nixpkgs and caches in across restarts. Defaults to $STATE_DIRECTORY, then $XDG_STATE_HOME/snix-bot."
+    )]
+    pub(crate) data_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        default_values = ["master"],
+        help = "Branches of nixpkgs to keep checked out. The first is used unless a command asks for another."
+    )]
+    pub(crate) channels: Vec<String>,
+    #[clap(
+        long,
+        env,
+        help = "Seconds between automatic updates of the nixpkgs channels. Never updates automatically if unset."
+    )]
+    pub(crate) update_interval: Option<u64>,
+    #[clap(
+        long,
+        env,
+        help = "ID of a Discord channel to report automatic nixpkgs updates in."
+    )]
+    pub(crate) admin_log_channel: Option<u64>,
+    #[clap(
+        long,
+        env,
+        default_value = "3600",
+        help = "Seconds of inactivity after which a user's /eval session is forgotten."
+    )]
+    pub(crate) session_idle_timeout: u64,
+    #[clap(
+        long,
+        env,
+        help = "Keep a separate /eval session per channel rather than one per user."
+    )]
+    pub(crate) per_channel_sessions: bool,
+    #[clap(
+        long,
+        env,
+        default_values = ["HOME=/homeless-shelter", "NIX_PATH=", "USER=nixbld"],
+        help = "NAME=VALUE environment variable that evaluations see through builtins.getEnv. Repeat the flag for each variable, as values may contain commas."
+    )]
+    pub(crate) eval_env: Vec<String>,
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "Names of the bot's own environment variables evaluations may see. TOKEN and GITHUB_TOKEN are never passed through."
+    )]
+    pub(crate) eval_env_passthrough: Vec<String>,
+    #[clap(
+        long,
+        env,
+        default_value = "2",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Longest an evaluation may run for, in seconds."
+    )]
+    pub(crate) max_eval_timeout: u64,
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "GUILD_ID=SECONDS overrides of the maximum evaluation time for specific guilds."
+    )]
+    pub(crate) guild_eval_timeout: Vec<GuildTimeout>,
+    #[clap(
+        long,
+        env,
+        default_value = "4096",
+        help = "Address space limit for the evaluation worker, in MiB."
+    )]
+    pub(crate) eval_memory_limit: u64,
+    #[clap(
+        long,
+        env,
+        default_value = "900",
+        help = "Longest building an index of nixpkgs, like who maintains which packages, may take, in seconds."
+    )]
+    pub(crate) index_timeout: u64,
+    #[clap(
+        long,
+        env,
+        help = "GitHub token to authenticate API requests with, for a higher rate limit."
+    )]
+    pub(crate) github_token: Option<String>,
+    #[clap(
+        long,
+        env,
+        default_value = "https://api.github.com",
+        help = "Base URL of the GitHub API."
+    )]
+    pub(crate) github_api_url: String,
+    #[clap(
+        long,
+        env,
+        default_value = "86400",
+        help = "Seconds to remember a GitHub user's avatar for."
+    )]
+    pub(crate) github_cache_ttl: u64,
+    #[clap(
+        long,
+        env,
+        help = "Re-evaluate code blocks evaluated from a message's context menu when the message is edited. Needs the privileged message content intent enabled in the developer portal."
+    )]
+    pub(crate) code_block_evaluation: bool,
+    #[clap(
+        long,
+        hide = true,
+        help = "Run as an evaluation worker, reading requests from stdin."
+    )]
+    pub(crate) eval_worker: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct GuildTimeout {
+    pub(crate) guild: u64,
+    pub(crate) seconds: u64,
+}
+
+impl FromStr for GuildTimeout {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (guild, seconds) = string
+            .split_once('=')
+            .ok_or_else(|| format!("Expected GUILD_ID=SECONDS, got {string:?}"))?;
+        Ok(Self {
+            guild: guild
+                .parse()
+                .map_err(|_| format!("Invalid guild ID {guild:?}"))?,
+            seconds: seconds
+                .parse()
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .ok_or_else(|| format!("Invalid number of seconds {seconds:?}"))?,
+        })
+    }
 }
 
 pub(crate) static ARGS: LazyLock<Args> = LazyLock::new(Args::parse);
+
+/// Where everything kept across restarts lives.
+pub(crate) static DATA_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
+    let variable = |name: &str| env::var_os(name).filter(|value| !value.is_empty());
+    ARGS.data_dir
+        .clone()
+        // systemd sets this from StateDirectory=, separating several directories with colons.
+        .or_else(|| variable("STATE_DIRECTORY").and_then(|paths| env::split_paths(&paths).next()))
+        .or_else(|| variable("XDG_STATE_HOME").map(|state| PathBuf::from(state).join("snix-bot")))
+        .or_else(|| variable("HOME").map(|home| PathBuf::from(home).join(".local/state/snix-bot")))
+        .unwrap_or_else(|| temp_dir().join("snix-bot"))
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guild_timeouts_are_parsed() {
+        let timeout: GuildTimeout = "123=45".parse().expect("It's a valid timeout.");
+        assert_eq!((timeout.guild, timeout.seconds), (123, 45));
+    }
+
+    #[test]
+    fn malformed_guild_timeouts_are_rejected() {
+        for malformed in [
+            "123", "=45", "abc=45", "123=", "123=abc", "123=-1", "123=45=6",
+        ] {
+            assert!(
+                malformed.parse::<GuildTimeout>().is_err(),
+                "{malformed:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn guild_timeouts_have_to_be_positive() {
+        let error = "123=0".parse::<GuildTimeout>().err();
+        assert_eq!(error.as_deref(), Some("Invalid number of seconds \"0\""));
+    }
+}
@@ -1,6 +1,8 @@
 use crate::Error;
-use crate::nixpkgs::NIXPKGS_REPO;
-use poise::{Context, command};
+use crate::args::ARGS;
+use crate::nixpkgs::{Activity, NIXPKGS_REPO, activity, channel_commit, update_channels};
+use poise::serenity_prelude::{Color, CreateEmbed};
+use poise::{Context, CreateReply, command};
 
 pub(crate) mod snix;
 
@@ -23,46 +25,79 @@ pub(crate) async fn ping(ctx: Context<'_, (), Error>) -> Result<(), Error> {
 pub(crate) async fn nixpkgs_pull(ctx: Context<'_, (), Error>) -> Result<(), Error> {
     // This can be expensive, so defer the interaction.
     ctx.defer().await?;
-    let guard = NIXPKGS_REPO.lock().await;
+    let summary = update_nixpkgs().await?;
+    ctx.say(summary).await?;
+    Ok(())
+}
 
-    guard
-        .as_ref()
-        .map(|repo| {
-            // fetch only tip
-            let mut remote = repo
-                .find_remote("origin")
-                .or(Err("Could not find the upstream remote!"))?;
-            let mut fetch_options = git2::FetchOptions::new();
-            fetch_options.depth(1);
-            remote
-                .fetch(&["master"], Some(&mut fetch_options), None)
-                .or(Err("The master branch is gone yo."))?;
+/// Moves every channel to its upstream tip and sets anything derived from the old checkouts
+/// to be rebuilt, returning a summary of where the channels ended up.
+pub(crate) async fn update_nixpkgs() -> Result<String, Error> {
+    let updated = update_channels().await?;
+    tokio::spawn(snix::maintainer::rebuild_maintainer_index());
 
-            let fetch_head = repo
-                .find_reference("FETCH_HEAD")
-                .or(Err("The commit I just fetched fucking *vanished*"))?;
-            let target = fetch_head
-                .target()
-                .ok_or_else(|| Error::from("No FETCH_HEAD target"))
-                .or(Err("Couldn't target the fetched head."))?;
+    let channels: Vec<String> = updated
+        .iter()
+        .map(|(channel, commit)| format!("`{channel}` to `{:.12}`", commit.to_string()))
+        .collect();
+    Ok(format!("Nixpkgs updated {}.", channels.join(", ")))
+}
 
-            // hard reset local branch to it
-            let mut reference = repo.find_reference("refs/heads/master").or(Err(
-                "Can't find the local master branch I plan to apply the work to!",
-            ))?;
-            reference
-                .set_target(target, "Reset to latest upstream")
-                .or(Err("Set target operation on the reflog shit itself..."))?;
-            repo.set_head("refs/heads/master")
-                .or(Err("Setting the new head shat itself."))?;
-            repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
-                .or(Err("Moving the local checkout failed."))?;
+#[command(
+    slash_command,
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub(crate) async fn status(ctx: Context<'_, (), Error>) -> Result<(), Error> {
+    let (activity, progress) = activity();
+    // Updates hold this for writing while they check every channel out, which can take longer
+    // than Discord waits for a reply.
+    let nixpkgs = NIXPKGS_REPO.try_read();
+    let updating = nixpkgs.is_err();
+    let set_up = nixpkgs.is_ok_and(|nixpkgs| nixpkgs.is_some());
+    let state = match &activity {
+        Activity::Cloning => String::from("Cloning"),
+        Activity::Fetching => String::from("Fetching"),
+        Activity::Failed { error, retry_at } => {
+            format!("Failed, trying again <t:{retry_at}:R>\n{error}")
+        }
+        Activity::Idle if updating => String::from("Updating"),
+        Activity::Idle if set_up => String::from("Ready"),
+        Activity::Idle => String::from("Not set up yet"),
+    };
 
-            Ok::<(), String>(())
+    let mut embed: CreateEmbed = CreateEmbed::new()
+        .title("Status")
+        .color(Color::from((35, 127, 235)))
+        .field("Nixpkgs", state, true);
+    if matches!(activity, Activity::Cloning | Activity::Fetching) {
+        embed = embed.field(
+            "Progress",
+            format!(
+                "{}/{} objects received ({} MiB)\n{}/{} deltas resolved",
+                progress.received_objects,
+                progress.total_objects,
+                progress.received_bytes / (1024 * 1024),
+                progress.indexed_deltas,
+                progress.total_deltas,
+            ),
+            true,
+        );
+    }
+    let channels: Vec<String> = ARGS
+        .channels
+        .iter()
+        .map(|channel| match channel_commit(channel) {
+            Some((revision, committed)) => {
+                format!("`{channel}`: `{revision:.12}` from <t:{committed}:R>")
+            }
+            None => format!("`{channel}`: not checked out"),
         })
-        .ok_or("Nixpkgs repo is not available!")??;
+        .collect();
+    embed = embed.field("Channels", channels.join("\n"), false);
 
-    ctx.say("Nixpkgs updated to upstream tip.").await?;
+    ctx.send(CreateReply::default().embed(embed).reply(true))
+        .await?;
     Ok(())
 }
 
@@ -0,0 +1,654 @@
+use crate::Error;
+use crate::args::ARGS;
+use crate::commands::snix::indexing::IndexBuilds;
+use crate::commands::snix::{
+    AUTOCOMPLETE_CHOICES, EMBED_DESCRIPTION_LIMIT, autocomplete_channel, check_value_for_errors,
+    chosen_channel, code_block, truncate,
+};
+use crate::nixpkgs::{
+    NIXPKGS_REPO, channel_checkout, default_channel, nixpkgs_revision, unavailable,
+};
+use git2::{ObjectType, Oid, Repository};
+use log::{error, info};
+use poise::serenity_prelude::{
+    AutocompleteChoice, Color, CreateAutocompleteResponse, CreateEmbed, CreateEmbedFooter,
+};
+use poise::{Context, CreateReply, command};
+use regex::Regex;
+use snix_eval::{EvalMode, Evaluation, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+use tokio::sync::Mutex;
+
+/// What a function's doc comment says about it.
+struct Documentation {
+    description: String,
+    signature: Option<String>,
+    examples: Vec<String>,
+    /// Where the function is defined in nixpkgs. `None` for builtins.
+    file: Option<String>,
+}
+
+/// The documented `lib` functions of some revision.
+#[derive(Default)]
+struct LibDocs {
+    /// Documentation by the function's full name, like `lib.attrsets.mapAttrs`.
+    functions: BTreeMap<String, Documentation>,
+    /// What `lib/default.nix` inherits straight into `lib`, by the name it gets there, like
+    /// `lib.mapAttrs` for `lib.attrsets.mapAttrs` or `lib.add` for `builtins.add`.
+    aliases: BTreeMap<String, String>,
+}
+
+/// The `lib` documentation of every revision some channel has checked out, by revision.
+static LIB_DOCS: LazyLock<Mutex<BTreeMap<String, Arc<LibDocs>>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+static BUILDS: IndexBuilds = IndexBuilds::new("the lib documentation");
+
+/// `lib/default.nix` making a file into an attrset of `lib`, like
+/// `attrsets = callLibs ./attrsets.nix;`.
+static CALL_LIBS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"([A-Za-z_][\w'-]*)\s*=\s*callLibs\s+\./([\w./-]+)\s*;")
+        .expect("The callLibs pattern is valid.")
+});
+
+/// `lib/default.nix` inheriting functions straight into `lib`, like
+/// `inherit (self.attrsets) mapAttrs;`.
+static INHERIT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"inherit\s*\(\s*(self\.[\w'-]+|builtins)\s*\)([^;]*);")
+        .expect("The inherit pattern is valid.")
+});
+
+/// The documentation of every builtin that has some, by name. This only depends on snix itself,
+/// so it's read once.
+static BUILTIN_DOCS: LazyLock<BTreeMap<String, Documentation>> = LazyLock::new(|| {
+    let evaluation = Evaluation::builder_impure().mode(EvalMode::Lazy).build();
+    let builtins =
+        check_value_for_errors(evaluation.evaluate("builtins", None)).and_then(|builtins| {
+            builtins
+                .to_attrs()
+                .map_err(|_| Error::from("builtins wasn't an attrset!"))
+        });
+    match builtins {
+        Ok(builtins) => builtins
+            .iter()
+            .filter_map(|(name, value)| match value {
+                Value::Builtin(builtin) => Some((
+                    String::from_utf8_lossy(name.as_bytes()).into_owned(),
+                    parse_doc_comment(builtin.documentation()?),
+                )),
+                _ => None,
+            })
+            .collect(),
+        Err(error) => {
+            error!("Failed to read the builtins' documentation: {error}");
+            BTreeMap::new()
+        }
+    }
+});
+
+/// The `lib` documentation of `channel`'s checkout, starting to read it in the background if it
+/// hasn't been since the checkout last moved. `None` until it has.
+async fn lib_docs(channel: Option<&str>) -> Result<Option<Arc<LibDocs>>, Error> {
+    channel_checkout(channel)?;
+    let revision =
+        nixpkgs_revision(channel.unwrap_or_else(default_channel)).ok_or_else(unavailable)?;
+    if let Some(docs) = LIB_DOCS.lock().await.get(&revision) {
+        return Ok(Some(Arc::clone(docs)));
+    }
+    if let Some(error) = BUILDS.failure(&revision) {
+        return Err(error);
+    }
+    BUILDS.start(revision.clone(), build_index(revision));
+    Ok(None)
+}
+
+async fn build_index(revision: String) -> Result<(), Error> {
+    let nixpkgs = NIXPKGS_REPO.read().await.clone().ok_or_else(unavailable)?;
+    info!("Reading the lib documentation of nixpkgs {revision}.");
+    // Read straight from the commit, so nothing has to be checked out for it.
+    let docs = tokio::task::spawn_blocking({
+        let revision = revision.clone();
+        move || read_lib_docs(&nixpkgs.repository(), &revision)
+    })
+    .await??;
+    info!(
+        "Finished reading the documentation of {} lib functions in nixpkgs {revision}.",
+        docs.functions.len()
+    );
+
+    // Only revisions some channel has checked out get asked about, so the documentation of any
+    // other revision is just taking up space.
+    let checked_out: Vec<String> = ARGS
+        .channels
+        .iter()
+        .filter_map(|channel| nixpkgs_revision(channel))
+        .collect();
+    let mut cached = LIB_DOCS.lock().await;
+    cached.retain(|read, _| checked_out.contains(read));
+    cached.insert(revision, Arc::new(docs));
+    Ok(())
+}
+
+/// Reads the doc comments out of `lib/default.nix` in the commit `revision` and every file it
+/// makes into an attrset of `lib`, along with what it inherits straight into `lib`.
+fn read_lib_docs(repository: &Repository, revision: &str) -> Result<LibDocs, Error> {
+    let tree = repository.find_commit(Oid::from_str(revision)?)?.tree()?;
+    let text = |path: &str| {
+        let blob = tree
+            .get_path(Path::new(path))
+            .and_then(|entry| entry.to_object(repository))
+            .and_then(|object| object.peel_to_blob())
+            .ok()?;
+        String::from_utf8(blob.content().to_vec()).ok()
+    };
+    let default = text("lib/default.nix").ok_or("nixpkgs doesn't have a lib/default.nix.")?;
+    let sets = lib_sets(&default);
+    let mut docs = LibDocs {
+        functions: BTreeMap::new(),
+        aliases: lib_aliases(&default),
+    };
+    // The few functions only defined in `lib` itself.
+    insert_docs(&mut docs.functions, "lib", &default, "lib/default.nix");
+
+    let lib = tree
+        .get_path(Path::new("lib"))?
+        .to_object(repository)?
+        .peel_to_tree()?;
+    for entry in lib.iter() {
+        let Some(name) = entry.name() else {
+            continue;
+        };
+        let file = match entry.kind() {
+            Some(ObjectType::Tree) => format!("lib/{name}/default.nix"),
+            Some(ObjectType::Blob) if name.ends_with(".nix") => format!("lib/{name}"),
+            _ => continue,
+        };
+        // Without any `callLibs` to go by, files are assumed to be named after their attrset.
+        let set = if sets.is_empty() {
+            Some(camel_case(name.trim_end_matches(".nix")))
+        } else {
+            sets.get(name).cloned()
+        };
+        let Some(set) = set.filter(|set| set != "default" && set != "tests") else {
+            continue;
+        };
+        let Some(source) = text(&file) else {
+            continue;
+        };
+        insert_docs(&mut docs.functions, &format!("lib.{set}"), &source, &file);
+    }
+    Ok(docs)
+}
+
+/// Adds the documentation of every function `source` documents under `prefix`.
+fn insert_docs(
+    functions: &mut BTreeMap<String, Documentation>,
+    prefix: &str,
+    source: &str,
+    file: &str,
+) {
+    for (name, comment) in doc_comments(source) {
+        let documentation = Documentation {
+            file: Some(file.to_string()),
+            ..parse_doc_comment(&comment)
+        };
+        functions.insert(format!("{prefix}.{name}"), documentation);
+    }
+}
+
+/// Which attrset of `lib` each file or directory in `lib` makes up, by its name there, according
+/// to the `callLibs` in `lib/default.nix`.
+fn lib_sets(default: &str) -> BTreeMap<String, String> {
+    CALL_LIBS
+        .captures_iter(default)
+        .map(|captures| {
+            let file = captures[2]
+                .trim_end_matches('/')
+                .trim_end_matches("/default.nix");
+            (file.to_string(), captures[1].to_string())
+        })
+        .collect()
+}
+
+/// What each function `lib/default.nix` inherits straight into `lib` is called where it comes
+/// from.
+fn lib_aliases(default: &str) -> BTreeMap<String, String> {
+    let mut aliases = BTreeMap::new();
+    for captures in INHERIT.captures_iter(default) {
+        let from = match captures[1].strip_prefix("self.") {
+            Some(set) => format!("lib.{set}"),
+            None => String::from("builtins"),
+        };
+        let names = captures[2]
+            .lines()
+            .map(|line| line.split_once('#').map_or(line, |(code, _)| code))
+            .flat_map(str::split_whitespace);
+        for name in names {
+            aliases.insert(format!("lib.{name}"), format!("{from}.{name}"));
+        }
+    }
+    aliases
+}
+
+/// Every RFC 145 doc comment in `source` along with the name bound right after it. Comments
+/// followed by anything other than a plain `name =` aren't attached to a function.
+fn doc_comments(source: &str) -> Vec<(String, String)> {
+    let mut documented = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("/**") {
+        let comment = &rest[start + 3..];
+        let Some(end) = comment.find("*/") else {
+            break;
+        };
+        rest = comment[end + 2..].trim_start();
+        let name: String = rest
+            .chars()
+            .take_while(|&character| {
+                character.is_ascii_alphanumeric() || matches!(character, '_' | '\'' | '-')
+            })
+            .collect();
+        if !name.is_empty() && rest[name.len()..].trim_start().starts_with('=') {
+            documented.push((name, comment[..end].to_string()));
+        }
+    }
+    documented
+}
+
+/// Splits a doc comment into its description, the code block under `# Type`, and the code
+/// blocks under `# Examples`. Any other section is left out.
+fn parse_doc_comment(comment: &str) -> Documentation {
+    let text = dedent(comment);
+    let mut description: Vec<&str> = Vec::new();
+    let mut signature: Option<String> = None;
+    let mut examples: Vec<String> = Vec::new();
+    let mut section = "";
+    let mut code: Option<Vec<&str>> = None;
+    for line in text.lines() {
+        let fence = line.trim_start().starts_with("```");
+        if let Some(lines) = &mut code {
+            if fence {
+                let block = lines.join("\n");
+                if section == "Type" {
+                    signature.get_or_insert(block);
+                } else {
+                    examples.push(block);
+                }
+                code = None;
+            } else {
+                lines.push(line);
+            }
+        } else if let Some(heading) = line.strip_prefix("# ") {
+            section = heading.trim();
+        } else if fence && matches!(section, "Type" | "Example" | "Examples") {
+            code = Some(Vec::new());
+        } else if section.is_empty() {
+            description.push(line);
+        }
+    }
+    Documentation {
+        description: description.join("\n").trim().to_string(),
+        signature,
+        examples,
+        file: None,
+    }
+}
+
+/// Strips the indentation every line of `text` shares, along with any blank lines around it.
+fn dedent(text: &str) -> String {
+    let indentation = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    text.lines()
+        .map(|line| line.get(indentation..).unwrap_or_else(|| line.trim_start()))
+        .collect::<Vec<&str>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// `lib` files are kebab-case, while the attrsets they make up are camelCase.
+fn camel_case(name: &str) -> String {
+    let mut words = name.split('-');
+    let first = words.next().unwrap_or_default().to_string();
+    words.fold(first, |mut camel, word| {
+        let mut characters = word.chars();
+        if let Some(first) = characters.next() {
+            camel.push(first.to_ascii_uppercase());
+            camel.push_str(characters.as_str());
+        }
+        camel
+    })
+}
+
+/// Looks `name` up as a `lib` function, with or without the `lib.` and the attrset it's in,
+/// and then as a builtin. Returns the full name along with the documentation.
+fn find_documentation<'a>(
+    lib_docs: &'a LibDocs,
+    name: &str,
+) -> Option<(String, &'a Documentation)> {
+    if let Some(builtin) = name.strip_prefix("builtins.") {
+        return find_builtin(builtin);
+    }
+    let qualified = if name.starts_with("lib.") {
+        name.to_string()
+    } else {
+        format!("lib.{name}")
+    };
+    // Functions `lib` inherits are documented where they come from.
+    let qualified = lib_docs
+        .aliases
+        .get(&qualified)
+        .cloned()
+        .unwrap_or(qualified);
+    if let Some(builtin) = qualified.strip_prefix("builtins.") {
+        return find_builtin(builtin);
+    }
+    if let Some(documentation) = lib_docs.functions.get(&qualified) {
+        return Some((qualified, documentation));
+    }
+    // Anything else is looked for in every attrset, as long as only one of them has it.
+    let function = &qualified["lib.".len()..];
+    if !function.contains('.') {
+        let mut found = lib_docs.functions.iter().filter(|(full_name, _)| {
+            full_name.rsplit_once('.').map(|(_, last)| last) == Some(function)
+        });
+        if let (Some((full_name, documentation)), None) = (found.next(), found.next()) {
+            return Some((full_name.clone(), documentation));
+        }
+    }
+    find_builtin(name)
+}
+
+fn find_builtin(name: &str) -> Option<(String, &'static Documentation)> {
+    BUILTIN_DOCS
+        .get_key_value(name)
+        .map(|(name, documentation)| (format!("builtins.{name}"), documentation))
+}
+
+fn noogle_url(name: &str) -> String {
+    format!("https://noogle.dev/f/{}", name.replace('.', "/"))
+}
+
+async fn autocomplete_function(
+    ctx: Context<'_, (), Error>,
+    partial: &str,
+) -> CreateAutocompleteResponse {
+    // Only ever what's been read already, as Discord gives up on autocompletion after 3 seconds.
+    let lib_docs = lib_docs(chosen_channel(ctx).as_deref())
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+
+    let partial = partial.trim().to_lowercase();
+    let matches = |name: &str| {
+        let name = name.to_lowercase();
+        name.starts_with(&partial)
+            || name
+                .rsplit_once('.')
+                .is_some_and(|(_, last)| last.starts_with(&partial))
+    };
+    let builtins = BUILTIN_DOCS.keys().map(|name| format!("builtins.{name}"));
+    let choices = lib_docs
+        .functions
+        .keys()
+        .cloned()
+        .chain(builtins)
+        .filter(|name| matches(name))
+        .take(AUTOCOMPLETE_CHOICES)
+        .map(AutocompleteChoice::from)
+        .collect();
+    CreateAutocompleteResponse::new().set_choices(choices)
+}
+
+#[command(
+    slash_command,
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub(crate) async fn doc(
+    ctx: Context<'_, (), Error>,
+    #[autocomplete = "autocomplete_function"]
+    #[description = "Function to look up, from lib or builtins"]
+    function: String,
+    #[autocomplete = "autocomplete_channel"]
+    #[description = "Nixpkgs channel to look in"]
+    channel: Option<String>,
+) -> Result<(), Error> {
+    let Some(lib_docs) = lib_docs(channel.as_deref()).await? else {
+        ctx.say("The lib documentation is still being read. Try again in a moment.")
+            .await?;
+        return Ok(());
+    };
+    let (name, documentation) = find_documentation(&lib_docs, function.trim())
+        .ok_or_else(|| format!("`{function}` isn't a documented lib function or builtin."))?;
+
+    let noogle = noogle_url(&name);
+    let link = format!("\n\n[View on Noogle]({noogle})");
+    let description = if documentation.description.is_empty() {
+        "No description."
+    } else {
+        documentation.description.as_str()
+    };
+    let mut embed: CreateEmbed = CreateEmbed::new()
+        .title(&name)
+        .url(&noogle)
+        .description(truncate(description, EMBED_DESCRIPTION_LIMIT - link.chars().count()) + &link)
+        .color(Color::from((35, 127, 235)));
+    if let Some(signature) = &documentation.signature {
+        embed = embed.field("Type", code_block(signature, ""), false);
+    }
+    if !documentation.examples.is_empty() {
+        embed = embed.field(
+            "Example",
+            code_block(&documentation.examples.join("\n\n"), "nix"),
+            false,
+        );
+    }
+    if let Some(file) = &documentation.file {
+        embed = embed.footer(CreateEmbedFooter::new(format!("Defined in {file}")));
+    }
+
+    ctx.send(CreateReply::default().embed(embed).reply(true))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn documented(description: &str) -> Documentation {
+        Documentation {
+            description: description.to_string(),
+            signature: None,
+            examples: Vec::new(),
+            file: None,
+        }
+    }
+
+    #[test]
+    fn doc_comments_are_attached_to_the_name_bound_after_them() {
+        let source = r"
+          /**
+            Flips a function's arguments.
+          */
+          flip' = f: a: b: f b a;
+
+          /** Not about any binding. */
+          inherit (builtins) head;
+
+          /**
+            The identity function.
+          */
+          id = x: x;
+        ";
+        let comments = doc_comments(source);
+        let names: Vec<&str> = comments.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["flip'", "id"]);
+        assert!(comments[0].1.contains("Flips a function's arguments."));
+    }
+
+    #[test]
+    fn unterminated_doc_comments_are_ignored() {
+        assert!(doc_comments("/** Never ends.\nid = x: x;").is_empty());
+    }
+
+    #[test]
+    fn doc_comments_are_split_into_sections() {
+        let comment = r"
+            Applies `f` to every element.
+
+            More about it.
+
+            # Inputs
+
+            `f`
+            : The function.
+
+            # Type
+
+            ```
+            map :: (a -> b) -> [a] -> [b]
+            ```
+
+            # Examples
+            :::{.example}
+            ```nix
+            map (x: x + 1) [ 1 2 ]
+            => [ 2 3 ]
+            ```
+
+            ```nix
+            map toString [ 1 ]
+            ```
+            :::
+        ";
+        let documentation = parse_doc_comment(comment);
+        assert_eq!(
+            documentation.description,
+            "Applies `f` to every element.\n\nMore about it."
+        );
+        assert_eq!(
+            documentation.signature.as_deref(),
+            Some("map :: (a -> b) -> [a] -> [b]")
+        );
+        assert_eq!(
+            documentation.examples,
+            ["map (x: x + 1) [ 1 2 ]\n=> [ 2 3 ]", "map toString [ 1 ]"]
+        );
+        assert!(documentation.file.is_none());
+    }
+
+    #[test]
+    fn files_are_matched_to_the_attrsets_lib_makes_them() {
+        let default = r"
+          trivial = callLibs ./trivial.nix;
+          fixedPoints = callLibs ./fixed-points.nix;
+          systems = callLibs ./systems;
+          fileset = callLibs ./fileset/default.nix;
+        ";
+        let sets = lib_sets(default);
+        assert_eq!(sets["trivial.nix"], "trivial");
+        assert_eq!(sets["fixed-points.nix"], "fixedPoints");
+        assert_eq!(sets["systems"], "systems");
+        assert_eq!(sets["fileset"], "fileset");
+    }
+
+    #[test]
+    fn inherited_functions_are_aliases() {
+        let default = r"
+          inherit (builtins) add
+            addErrorContext; # Not a lib function.
+          inherit (self.attrsets) mapAttrs
+            # Nor is this.
+            filterAttrs;
+        ";
+        let aliases = lib_aliases(default);
+        assert_eq!(aliases["lib.add"], "builtins.add");
+        assert_eq!(aliases["lib.addErrorContext"], "builtins.addErrorContext");
+        assert_eq!(aliases["lib.mapAttrs"], "lib.attrsets.mapAttrs");
+        assert_eq!(aliases["lib.filterAttrs"], "lib.attrsets.filterAttrs");
+        assert_eq!(aliases.len(), 4);
+    }
+
+    #[test]
+    fn functions_are_found_by_any_of_their_names() {
+        let lib_docs = LibDocs {
+            functions: BTreeMap::from([
+                (
+                    String::from("lib.attrsets.mapAttrs"),
+                    documented("attrsets"),
+                ),
+                (String::from("lib.lists.count"), documented("lists")),
+                (String::from("lib.strings.count"), documented("strings")),
+                (
+                    String::from("lib.strings.toSentenceCase"),
+                    documented("strings"),
+                ),
+                (String::from("lib.onlyInLib"), documented("lib")),
+            ]),
+            aliases: BTreeMap::from([(
+                String::from("lib.mapAttrs"),
+                String::from("lib.attrsets.mapAttrs"),
+            )]),
+        };
+        let find = |name| find_documentation(&lib_docs, name).map(|(name, _)| name);
+        assert_eq!(
+            find("lib.mapAttrs").as_deref(),
+            Some("lib.attrsets.mapAttrs")
+        );
+        assert_eq!(find("mapAttrs").as_deref(), Some("lib.attrsets.mapAttrs"));
+        assert_eq!(find("lib.lists.count").as_deref(), Some("lib.lists.count"));
+        assert_eq!(find("onlyInLib").as_deref(), Some("lib.onlyInLib"));
+        assert_eq!(
+            find("toSentenceCase").as_deref(),
+            Some("lib.strings.toSentenceCase")
+        );
+    }
+
+    #[test]
+    fn lib_docs_are_read_from_the_commit() {
+        let directory = tempfile::tempdir().expect("A temporary directory can be created.");
+        let repository = Repository::init(directory.path()).expect("A repository can be made.");
+        let files = [
+            (
+                "lib/default.nix",
+                "attrsets = callLibs ./attrsets.nix;\nsystems = callLibs ./systems;\ninherit (self.attrsets) mapAttrs;",
+            ),
+            ("lib/attrsets.nix", "/** Maps. */\nmapAttrs = f: set: set;"),
+            (
+                "lib/systems/default.nix",
+                "/** Elaborates. */\nelaborate = x: x;",
+            ),
+            ("lib/unused.nix", "/** Not in lib. */\nunused = x: x;"),
+        ];
+        let mut index = repository.index().expect("The repository has an index.");
+        for (path, contents) in files {
+            let file = directory.path().join(path);
+            fs::create_dir_all(file.parent().expect("Files are in lib.")).expect("lib is made.");
+            fs::write(&file, contents).expect("The file is written.");
+            index.add_path(Path::new(path)).expect("The file is added.");
+        }
+        let tree = repository
+            .find_tree(index.write_tree().expect("The tree is written."))
+            .expect("The tree is there.");
+        let signature = git2::Signature::now("test", "test@example.com").expect("It's valid.");
+        let commit = repository
+            .commit(None, &signature, &signature, "lib", &tree, &[])
+            .expect("The commit is made.");
+
+        let docs = read_lib_docs(&repository, &commit.to_string()).expect("lib is read.");
+        let names: Vec<&str> = docs.functions.keys().map(String::as_str).collect();
+        assert_eq!(names, ["lib.attrsets.mapAttrs", "lib.systems.elaborate"]);
+        let mapped = &docs.functions["lib.attrsets.mapAttrs"];
+        assert_eq!(mapped.description, "Maps.");
+        assert_eq!(mapped.file.as_deref(), Some("lib/attrsets.nix"));
+        assert_eq!(docs.aliases["lib.mapAttrs"], "lib.attrsets.mapAttrs");
+    }
+}
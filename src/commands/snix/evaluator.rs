@@ -0,0 +1,948 @@
+use crate::Error;
+use crate::args::ARGS;
+use crate::commands::snix::check_value_for_errors;
+use crate::commands::snix::io::{EVAL_ENV, NixpkgsIo};
+use crate::commands::snix::repl::Assignment;
+use crate::nixpkgs::{NIXPKGS_GENERATION, NIXPKGS_REPO, channel_checkout};
+use log::{debug, error, warn};
+use poise::ChoiceParameter;
+use poise::serenity_prelude::GuildId;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use snix_eval::{EvalMode, GlobalsMap, Value};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::rc::Rc;
+use std::sync::LazyLock;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, timeout};
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub(crate) const SYSTEM: &str = "x86_64-linux";
+
+// Evaluations run in a separate copy of the bot started with `--eval-worker`, so a runaway
+// expression can be killed outright instead of burning CPU and memory inside the bot itself.
+// `Value` and `GlobalsMap` aren't thread safe anyway, so the worker keeps them on its one thread.
+static WORKER: LazyLock<Mutex<Option<Worker>>> = LazyLock::new(|| Mutex::new(None));
+// Long running evaluations, like building indexes of nixpkgs, get a worker of their own so they
+// don't hold up everyone's `/eval`.
+static BACKGROUND_WORKER: LazyLock<Mutex<Option<Worker>>> = LazyLock::new(|| Mutex::new(None));
+// Autocompletion gets one too, so suggestions never wait on an `/eval`, and one given up on
+// halfway never costs `/eval` its warm worker.
+static AUTOCOMPLETE_WORKER: LazyLock<Mutex<Option<Worker>>> = LazyLock::new(|| Mutex::new(None));
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize, ChoiceParameter)]
+pub(crate) enum Mode {
+    /// Force the whole result, as `nix eval` would.
+    #[default]
+    Strict,
+    /// Only force the outermost value, leaving everything inside it unevaluated.
+    Lazy,
+}
+
+impl From<Mode> for EvalMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Strict => EvalMode::Strict,
+            Mode::Lazy => EvalMode::Lazy,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ChoiceParameter)]
+pub(crate) enum Output {
+    /// Nix syntax, formatted with alejandra.
+    #[default]
+    Nix,
+    /// The result of `builtins.toJSON`, pretty printed.
+    #[name = "JSON"]
+    Json,
+    /// A string result as it is, without quotes or escapes.
+    Raw,
+    /// What kind of value the result is and what it looks like, without showing all of it.
+    Type,
+}
+
+impl Output {
+    /// The language to highlight a code block of this output as.
+    pub(crate) fn language(self) -> &'static str {
+        match self {
+            Output::Nix => "nix",
+            Output::Json => "json",
+            Output::Raw | Output::Type => "",
+        }
+    }
+
+    pub(crate) fn file_name(self) -> &'static str {
+        match self {
+            Output::Nix => "result.nix",
+            Output::Json => "result.json",
+            Output::Raw => "result.txt",
+            Output::Type => "type.txt",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct EvalOptions {
+    pub(crate) mode: Mode,
+    pub(crate) output: Output,
+    pub(crate) timeout: Duration,
+    /// The nixpkgs checkout to evaluate in.
+    pub(crate) checkout: PathBuf,
+}
+
+impl EvalOptions {
+    /// Builds the options for an evaluation in `guild`, refusing timeouts above what the guild
+    /// allows and channels that aren't configured. Without a requested timeout, the guild's
+    /// maximum is used.
+    pub(crate) fn new(
+        guild: Option<GuildId>,
+        channel: Option<&str>,
+        mode: Option<Mode>,
+        output: Option<Output>,
+        timeout: Option<u64>,
+    ) -> Result<Self, Error> {
+        let max_timeout = max_eval_timeout(guild);
+        let timeout = timeout.unwrap_or(max_timeout);
+        if timeout > max_timeout {
+            return Err(Error::from(format!(
+                "The longest an evaluation may run for here is {max_timeout} seconds."
+            )));
+        }
+        Ok(Self {
+            mode: mode.unwrap_or_default(),
+            output: output.unwrap_or_default(),
+            timeout: Duration::from_secs(timeout),
+            checkout: channel_checkout(channel)?,
+        })
+    }
+}
+
+fn max_eval_timeout(guild: Option<GuildId>) -> u64 {
+    guild
+        .and_then(|guild| {
+            ARGS.guild_eval_timeout
+                .iter()
+                .find(|guild_timeout| guild_timeout.guild == guild.get())
+        })
+        .map_or(ARGS.max_eval_timeout, |guild_timeout| guild_timeout.seconds)
+}
+
+#[derive(Serialize, Deserialize)]
+struct WorkerRequest {
+    expression: String,
+    bindings: Vec<Assignment>,
+    mode: Mode,
+    output: Output,
+    cpu_seconds: u64,
+    checkout: PathBuf,
+    // The worker can't see our `NIXPKGS_GENERATION`, so it gets told with every request.
+    generation: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WorkerResponse {
+    result: Result<String, String>,
+    traces: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl WorkerResponse {
+    fn failed(error: &str) -> Self {
+        Self {
+            result: Err(error.to_string()),
+            traces: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// A successful evaluation, along with whatever it printed and warned about on the way.
+pub(crate) struct EvalOutput {
+    pub(crate) value: String,
+    pub(crate) traces: Vec<String>,
+    pub(crate) warnings: Vec<String>,
+}
+
+/// An evaluation that was stopped for running too long or using too much, rather than one that
+/// failed by itself. Trying the same again, or any part of it, is just as likely to be stopped.
+#[derive(Debug)]
+pub(crate) struct Stopped(String);
+
+impl Display for Stopped {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+struct Worker {
+    // Held so the process is killed when the worker is dropped.
+    _process: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    // Set while a request is waiting on its response. If it's still set when the next request
+    // comes along, the previous one was abandoned and its response would be read as the new one's.
+    in_flight: bool,
+}
+
+/// Runs `program` the way workers are run, without anything from the bot's environment.
+fn worker_command(program: &Path) -> Command {
+    let mut command = Command::new(program);
+    command
+        // Nothing from the bot's environment should be visible to evaluations, but the
+        // worker has to agree with us on where the temporary directory is.
+        .env_clear()
+        .envs(std::env::var_os("TMPDIR").map(|directory| ("TMPDIR", directory)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true);
+    command
+}
+
+impl Worker {
+    fn spawn() -> io::Result<Self> {
+        debug!("Spawning a new evaluation worker.");
+        let mut command = worker_command(&std::env::current_exe()?);
+        command
+            .arg("--eval-worker")
+            .args(["--log-level", ARGS.log_level.as_str()])
+            .args(["--dependency-log-level", ARGS.dependency_log_level.as_str()])
+            .arg("--eval-memory-limit")
+            .arg(ARGS.eval_memory_limit.to_string());
+        // Passthrough variables are resolved here, as the worker won't have them to look up.
+        for (name, value) in EVAL_ENV.iter() {
+            command.arg("--eval-env").arg(format!("{name}={value}"));
+        }
+        let mut process = command.spawn()?;
+        let stdin = process.stdin.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        let stdout = process.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        Ok(Self {
+            _process: process,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+            in_flight: false,
+        })
+    }
+
+    async fn exchange(&mut self, request: &WorkerRequest) -> io::Result<WorkerResponse> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        self.in_flight = true;
+        self.stdin.write_all(&line).await?;
+        self.stdin.flush().await?;
+        let response = self
+            .stdout
+            .next_line()
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        self.in_flight = false;
+        Ok(serde_json::from_str(&response)?)
+    }
+}
+
+/// How much of what got traced before a failure is shown along with the error.
+const ERROR_TRACE_LIMIT: usize = 2048;
+
+/// `error` followed by whatever got traced before it, which is usually what explains it. Only
+/// the end of a long trace is kept, as that's what came right before the failure.
+fn with_traces(error: &str, traces: &[String]) -> String {
+    let trace = traces.join("\n");
+    let length = trace.chars().count();
+    if length <= ERROR_TRACE_LIMIT {
+        return format!("{error}\n**Trace**\n```\n{trace}\n```");
+    }
+    let end: String = trace.chars().skip(length - ERROR_TRACE_LIMIT).collect();
+    format!(
+        "{error}\n**Trace** (only the last {ERROR_TRACE_LIMIT} of {length} characters)\n```\n{end}\n```"
+    )
+}
+
+/// How long a worker gets to import nixpkgs for a checkout it hasn't evaluated in yet. That
+/// doesn't count against the evaluation's own timeout, which would otherwise be spent on it.
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub(crate) async fn evaluate_expression(
+    expression: String,
+    bindings: Vec<Assignment>,
+    options: EvalOptions,
+) -> Result<EvalOutput, Error> {
+    // Keeps the checkout from being moved partway through the evaluation.
+    let _nixpkgs = NIXPKGS_REPO.read().await;
+    evaluate_with(&WORKER, expression, bindings, options).await
+}
+
+/// Evaluates `expression` on the background worker, for evaluations expected to take far longer
+/// than anyone would wait on an `/eval`. These don't hold up pulls, so they have to run in a
+/// checkout pulls don't move, like the one `pinned_checkout` gives.
+pub(crate) async fn evaluate_in_background(
+    expression: String,
+    options: EvalOptions,
+) -> Result<EvalOutput, Error> {
+    evaluate_with(&BACKGROUND_WORKER, expression, Vec::new(), options).await
+}
+
+async fn evaluate_with(
+    worker: &Mutex<Option<Worker>>,
+    expression: String,
+    bindings: Vec<Assignment>,
+    options: EvalOptions,
+) -> Result<EvalOutput, Error> {
+    evaluate_on(&mut *worker.lock().await, expression, bindings, options).await
+}
+
+async fn evaluate_on(
+    worker: &mut Option<Worker>,
+    expression: String,
+    bindings: Vec<Assignment>,
+    options: EvalOptions,
+) -> Result<EvalOutput, Error> {
+    let eval_timeout: Duration = options.timeout;
+    let generation = NIXPKGS_GENERATION.load(Ordering::Acquire);
+    let warm_up = WorkerRequest {
+        expression: String::from("null"),
+        bindings: Vec::new(),
+        mode: Mode::Lazy,
+        output: Output::Nix,
+        cpu_seconds: WARM_UP_TIMEOUT.as_secs(),
+        checkout: options.checkout.clone(),
+        generation,
+    };
+    let request = WorkerRequest {
+        expression,
+        bindings,
+        mode: options.mode,
+        output: options.output,
+        cpu_seconds: eval_timeout.as_secs(),
+        checkout: options.checkout,
+        generation,
+    };
+
+    // A request whose caller went away before the response came back leaves the response
+    // unread, where the next request would take it for its own.
+    if worker.as_ref().is_some_and(|running| running.in_flight) {
+        debug!("Replacing an evaluation worker left waiting on an abandoned request.");
+        *worker = None;
+    }
+    if worker.is_none() {
+        *worker = Some(Worker::spawn().map_err(|error| {
+            error!("Failed to spawn an evaluation worker: {error}");
+            "Couldn't start an evaluation worker."
+        })?);
+    }
+    let running = worker
+        .as_mut()
+        .ok_or("Couldn't start an evaluation worker.")?;
+
+    // Answered straight away once the worker has nixpkgs loaded for the checkout, and otherwise
+    // loads it on a deadline of its own.
+    match timeout(WARM_UP_TIMEOUT, running.exchange(&warm_up)).await {
+        Ok(Ok(response)) => {
+            response.result?;
+        }
+        Ok(Err(exchange_error)) => {
+            warn!("Evaluation worker went away while importing nixpkgs: {exchange_error}");
+            *worker = None;
+            return Err(Error::from(Stopped(String::from(
+                "Importing nixpkgs was killed for exceeding the worker's resource limits.",
+            ))));
+        }
+        Err(_) => {
+            *worker = None;
+            return Err(Error::from(Stopped(format!(
+                "Importing nixpkgs took longer than {} seconds.",
+                WARM_UP_TIMEOUT.as_secs()
+            ))));
+        }
+    }
+
+    let exchange = timeout(eval_timeout, running.exchange(&request)).await;
+    match exchange {
+        Ok(Ok(response)) => match response.result {
+            Ok(value) => Ok(EvalOutput {
+                value,
+                traces: response.traces,
+                warnings: response.warnings,
+            }),
+            Err(error) if !response.traces.is_empty() => {
+                Err(Error::from(with_traces(&error, &response.traces)))
+            }
+            Err(error) => Err(Error::from(error)),
+        },
+        Ok(Err(exchange_error)) => {
+            warn!("Evaluation worker went away: {exchange_error}");
+            *worker = None;
+            Err(Error::from(Stopped(String::from(
+                "Evaluation was killed for exceeding its resource limits.",
+            ))))
+        }
+        Err(_) => {
+            // Dropping the worker kills it, taking the runaway evaluation with it.
+            *worker = None;
+            Err(Error::from(Stopped(format!(
+                "Evaluation took too long. Max eval time is {} seconds.",
+                eval_timeout.as_secs()
+            ))))
+        }
+    }
+}
+
+/// How long autocompletion waits on names it doesn't have yet. Discord only gives it three
+/// seconds to answer, network included.
+const AUTOCOMPLETE_WAIT: Duration = Duration::from_secs(2);
+
+/// How long listing an attrset's names for autocompletion may take. Lookups that outlast
+/// `AUTOCOMPLETE_WAIT` still finish, so their names are there by the next keystroke.
+const AUTOCOMPLETE_EVAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many different attrsets to remember the names of before starting over.
+const CACHED_ATTRIBUTE_SETS: usize = 256;
+
+static ATTRIBUTE_NAMES: LazyLock<Mutex<FxHashMap<(u64, String), Vec<String>>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+/// The attribute names of the attrset `expression` evaluates to, for autocompletion. These are
+/// cached per checkout, as the same attrsets get asked about with every keystroke. Empty when
+/// they can't be had in time.
+pub(crate) async fn attribute_names(expression: &str) -> Vec<String> {
+    let key = (
+        NIXPKGS_GENERATION.load(Ordering::Acquire),
+        expression.to_string(),
+    );
+    if let Some(names) = ATTRIBUTE_NAMES.lock().await.get(&key) {
+        return names.clone();
+    }
+
+    // Spawned so that giving up on the wait doesn't abandon the evaluation partway through.
+    let lookup = tokio::spawn(look_up_attribute_names(key));
+    match timeout(AUTOCOMPLETE_WAIT, lookup).await {
+        Ok(Ok(Ok(names))) => names,
+        Ok(Ok(Err(error))) => {
+            debug!("Couldn't list attribute names for autocompletion: {error}");
+            Vec::new()
+        }
+        Ok(Err(_)) | Err(_) => Vec::new(),
+    }
+}
+
+async fn look_up_attribute_names(
+    (generation, expression): (u64, String),
+) -> Result<Vec<String>, Error> {
+    // Keystrokes that come in while a lookup is running go without suggestions rather than
+    // queueing up behind it, and so do those that come in while nixpkgs is being updated.
+    let mut worker = AUTOCOMPLETE_WORKER
+        .try_lock()
+        .or(Err("Another autocompletion is still running."))?;
+    let _nixpkgs = NIXPKGS_REPO
+        .try_read()
+        .or(Err("Nixpkgs is being updated."))?;
+    let options = EvalOptions {
+        mode: Mode::Strict,
+        output: Output::Json,
+        timeout: AUTOCOMPLETE_EVAL_TIMEOUT,
+        checkout: channel_checkout(None)?,
+    };
+    let output = evaluate_on(
+        &mut worker,
+        format!("builtins.attrNames ({expression})"),
+        Vec::new(),
+        options,
+    )
+    .await?;
+    let names: Vec<String> = serde_json::from_str(&output.value)?;
+
+    let mut cache = ATTRIBUTE_NAMES.lock().await;
+    if cache.len() >= CACHED_ATTRIBUTE_SETS {
+        cache.clear();
+    }
+    cache.insert((generation, expression), names.clone());
+    Ok(names)
+}
+
+/// Entry point for the `--eval-worker` process. Answers one JSON request per line on stdin
+/// with one JSON response per line on stdout until stdin closes.
+pub(crate) fn run_worker() {
+    limit_memory(ARGS.eval_memory_limit);
+    let mut warm: FxHashMap<PathBuf, WarmGlobals> = FxHashMap::default();
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else { break };
+        let response: WorkerResponse = match serde_json::from_str::<WorkerRequest>(&line) {
+            Ok(request) => {
+                limit_cpu(request.cpu_seconds);
+                handle_request(&mut warm, request)
+            }
+            Err(error) => WorkerResponse::failed(&format!("Malformed evaluation request: {error}")),
+        };
+        let mut stdout = io::stdout().lock();
+        let written = serde_json::to_writer(&mut stdout, &response)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(stdout))
+            .and_then(|()| stdout.flush());
+        if written.is_err() {
+            break;
+        }
+    }
+}
+
+fn limit_memory(mebibytes: u64) {
+    let bytes = mebibytes.saturating_mul(1024 * 1024);
+    let limit = libc::rlimit {
+        rlim_cur: bytes,
+        rlim_max: bytes,
+    };
+    // SAFETY: `limit` is a valid rlimit which outlives the call.
+    if unsafe { libc::setrlimit(libc::RLIMIT_AS, &raw const limit) } != 0 {
+        warn!(
+            "Couldn't limit worker memory: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+fn limit_cpu(seconds: u64) {
+    // RLIMIT_CPU counts CPU time over the whole life of the process, so each request gets
+    // its budget on top of whatever earlier requests have already used.
+    // SAFETY: `rusage` is plain old data, so all zeroes is a valid value.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: `usage` is a valid rusage which outlives the call.
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &raw mut usage) };
+    let used = usage.ru_utime.tv_sec.saturating_add(usage.ru_stime.tv_sec);
+    let limit = libc::rlimit {
+        // The extra second accounts for the partial seconds `tv_sec` rounds away.
+        rlim_cur: u64::try_from(used)
+            .unwrap_or(0)
+            .saturating_add(seconds)
+            .saturating_add(1),
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    // SAFETY: `limit` is a valid rlimit which outlives the call.
+    if unsafe { libc::setrlimit(libc::RLIMIT_CPU, &raw const limit) } != 0 {
+        warn!(
+            "Couldn't limit worker CPU time: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// How many checkouts to keep the globals of at once. Each costs as much memory as importing
+/// `pkgs` does.
+const WARM_CHECKOUTS: usize = 3;
+
+/// The nixpkgs globals, kept between evaluations so `lib` and `pkgs` only get imported once per
+/// checkout rather than once per request.
+struct WarmGlobals {
+    generation: u64,
+    last_used: Instant,
+    globals: Rc<GlobalsMap>,
+    lib: Value,
+    pkgs: Value,
+}
+
+fn handle_request(
+    warm: &mut FxHashMap<PathBuf, WarmGlobals>,
+    request: WorkerRequest,
+) -> WorkerResponse {
+    let checkout = request.checkout.as_path();
+    let mut warnings = Vec::new();
+    let (globals, env) = match session_env(
+        warm,
+        checkout,
+        request.generation,
+        request.bindings,
+        &mut warnings,
+    ) {
+        Ok(session) => session,
+        Err(error) => return WorkerResponse::failed(&error.to_string()),
+    };
+
+    let (result, printed) = capture_output(|| {
+        let (value, globals) = evaluate(
+            &request.expression,
+            checkout,
+            Some(globals),
+            &env,
+            request.mode.into(),
+            &mut warnings,
+        )?;
+        render(value, checkout, globals, request.output)
+    });
+    WorkerResponse {
+        result: result.map_err(|error| error.to_string()),
+        traces: printed.lines().map(String::from).collect(),
+        warnings,
+    }
+}
+
+fn render(
+    value: Value,
+    checkout: &Path,
+    globals: Rc<GlobalsMap>,
+    output: Output,
+) -> Result<String, Error> {
+    match output {
+        Output::Nix => Ok(format!("{value}")),
+        Output::Json => {
+            let mut env: FxHashMap<SmolStr, Value> = FxHashMap::default();
+            env.insert("result".into(), value);
+            let (json, _) = evaluate(
+                "builtins.toJSON result",
+                checkout,
+                Some(globals),
+                &env,
+                EvalMode::Strict,
+                &mut Vec::new(),
+            )
+            .map_err(|error| format!("The result can't be converted to JSON: {error}"))?;
+            let json = nix_string(&json)?;
+            // `builtins.toJSON` puts everything on one line, which is unreadable for anything big.
+            Ok(serde_json::from_str::<serde_json::Value>(&json)
+                .and_then(|parsed| serde_json::to_string_pretty(&parsed))
+                .unwrap_or(json))
+        }
+        Output::Raw => nix_string(&value),
+        Output::Type => describe(value, checkout, globals),
+    }
+}
+
+// Everything except the thunk and builtin checks can be asked of Nix itself.
+const DESCRIBE_EXPRESSION: &str = r#"
+let
+  type = builtins.typeOf value;
+in
+  builtins.toJSON ({inherit type;}
+    // (if type == "set" then {names = builtins.attrNames value;} else {})
+    // (if type == "list" then {length = builtins.length value;} else {})
+    // (if type == "lambda" then {formals = builtins.functionArgs value;} else {})
+    // (if type == "string" then {hasContext = builtins.hasContext value;} else {})
+    // (if builtins.elem type ["int" "float" "bool" "null" "path"] then {shown = toString value;} else {}))
+"#;
+
+/// How many attribute names to list before giving up and just counting them.
+const DESCRIBED_NAMES: usize = 50;
+
+#[derive(Deserialize)]
+struct Shape {
+    r#type: String,
+    names: Option<Vec<String>>,
+    length: Option<usize>,
+    formals: Option<BTreeMap<String, bool>>,
+    #[serde(rename = "hasContext")]
+    has_context: Option<bool>,
+    shown: Option<String>,
+}
+
+fn describe(value: Value, checkout: &Path, globals: Rc<GlobalsMap>) -> Result<String, Error> {
+    let unevaluated = matches!(value, Value::Thunk(_));
+    let builtin = matches!(value, Value::Builtin(_));
+    let mut env: FxHashMap<SmolStr, Value> = FxHashMap::default();
+    env.insert("value".into(), value);
+    let (shape, _) = evaluate(
+        DESCRIBE_EXPRESSION,
+        checkout,
+        Some(globals),
+        &env,
+        EvalMode::Lazy,
+        &mut Vec::new(),
+    )?;
+    let shape: Shape = serde_json::from_str(&nix_string(&shape)?)?;
+
+    let mut description = match shape {
+        Shape {
+            names: Some(names), ..
+        } => {
+            let mut description = format!("attribute set with {} attributes", names.len());
+            if !names.is_empty() && names.len() <= DESCRIBED_NAMES {
+                description = format!("{description}: {}", names.join(", "));
+            }
+            description
+        }
+        Shape {
+            length: Some(length),
+            ..
+        } => format!("list of {length} elements"),
+        Shape { .. } if builtin => String::from("builtin function"),
+        Shape {
+            formals: Some(formals),
+            ..
+        } if formals.is_empty() => String::from("lambda taking a single argument"),
+        Shape {
+            formals: Some(formals),
+            ..
+        } => {
+            let formals: Vec<String> = formals
+                .into_iter()
+                .map(|(name, has_default)| {
+                    if has_default {
+                        format!("{name} ? <default>")
+                    } else {
+                        name
+                    }
+                })
+                .collect();
+            format!(
+                "lambda taking an attribute set {{ {} }}",
+                formals.join(", ")
+            )
+        }
+        Shape {
+            has_context: Some(true),
+            ..
+        } => String::from("string with context"),
+        Shape {
+            r#type: kind,
+            shown: Some(shown),
+            ..
+        } => format!("{kind} ({shown})"),
+        Shape { r#type: kind, .. } => kind,
+    };
+    if unevaluated {
+        description = format!("thunk, which evaluates to a {description}");
+    }
+    Ok(description)
+}
+
+fn nix_string(value: &Value) -> Result<String, Error> {
+    let string = value.to_str().map_err(|_| {
+        format!(
+            "The result is a {} rather than a string, so it can't be shown raw.",
+            value.type_of()
+        )
+    })?;
+    Ok(String::from_utf8_lossy(string.as_bytes()).into_owned())
+}
+
+/// Runs `evaluate` with stdout and stderr pointed at a temporary file, returning whatever got
+/// printed alongside its result. That's where snix sends `builtins.trace` messages.
+fn capture_output<T>(evaluate: impl FnOnce() -> T) -> (T, String) {
+    let Ok(mut capture) = tempfile::tempfile() else {
+        warn!("Couldn't create a file to capture evaluation output in.");
+        return (evaluate(), String::new());
+    };
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    // SAFETY: duplicating the standard streams, which stay open for the life of the process.
+    let saved = unsafe {
+        [
+            libc::dup(libc::STDOUT_FILENO),
+            libc::dup(libc::STDERR_FILENO),
+        ]
+    };
+    for stream in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: both descriptors are open; `capture` outlives the redirection.
+        unsafe { libc::dup2(capture.as_raw_fd(), stream) };
+    }
+
+    let result = evaluate();
+
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    for (stream, original) in [libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        .into_iter()
+        .zip(saved)
+    {
+        // SAFETY: `original` was duplicated from `stream` above and is only closed here.
+        unsafe {
+            libc::dup2(original, stream);
+            libc::close(original);
+        }
+    }
+
+    let mut printed = Vec::new();
+    if let Err(error) = capture
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| capture.read_to_end(&mut printed))
+    {
+        warn!("Couldn't read back captured evaluation output: {error}");
+    }
+    (result, String::from_utf8_lossy(&printed).into_owned())
+}
+
+/// Sets up the environment for a request: `lib`, `pkgs` and the session's bindings. Bindings
+/// that no longer evaluate are left out, with a warning saying so.
+fn session_env(
+    warm: &mut FxHashMap<PathBuf, WarmGlobals>,
+    checkout: &Path,
+    generation: u64,
+    bindings: Vec<Assignment>,
+    warnings: &mut Vec<String>,
+) -> Result<(Rc<GlobalsMap>, FxHashMap<SmolStr, Value>), Error> {
+    warm.retain(|_, globals| globals.generation == generation);
+    if !warm.contains_key(checkout) {
+        debug!(
+            "Importing nixpkgs globals from {} for checkout generation {generation}.",
+            checkout.display()
+        );
+        let globals = warm_up(checkout, generation)?;
+        if warm.len() >= WARM_CHECKOUTS {
+            let least_recently_used = warm
+                .iter()
+                .min_by_key(|(_, globals)| globals.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(path) = least_recently_used {
+                warm.remove(&path);
+            }
+        }
+        warm.insert(checkout.to_path_buf(), globals);
+    }
+    let warm = warm
+        .get_mut(checkout)
+        .ok_or("The nixpkgs globals went missing.")?;
+    warm.last_used = Instant::now();
+
+    let mut env: FxHashMap<SmolStr, Value> = FxHashMap::default();
+    env.insert("lib".into(), warm.lib.clone());
+    env.insert("pkgs".into(), warm.pkgs.clone());
+
+    // Session bindings are added in the order they were made, so each one can refer to
+    // the ones before it. A binding that no longer evaluates is skipped rather than
+    // failing every later evaluation in the session, but the user gets told about it.
+    for binding in bindings {
+        match evaluate(
+            &binding.value,
+            checkout,
+            Some(Rc::clone(&warm.globals)),
+            &env,
+            EvalMode::Lazy,
+            &mut Vec::new(),
+        ) {
+            Ok((value, _)) => {
+                env.insert(binding.name.as_str().into(), value);
+            }
+            Err(error) => warnings.push(format!(
+                "Session binding `{}` no longer evaluates, so it was left out: {error}",
+                binding.name
+            )),
+        }
+    }
+
+    Ok((Rc::clone(&warm.globals), env))
+}
+
+fn warm_up(checkout: &Path, generation: u64) -> Result<WarmGlobals, Error> {
+    let mut env: FxHashMap<SmolStr, Value> = FxHashMap::default();
+    let (lib, globals) = evaluate(
+        "import ./lib",
+        checkout,
+        None,
+        &env,
+        EvalMode::Lazy,
+        &mut Vec::new(),
+    )?;
+    env.insert("lib".into(), lib.clone());
+    let (pkgs, _) = evaluate(
+        &format!("import ./pkgs/top-level/default.nix {{localSystem = \"{SYSTEM}\";}}"),
+        checkout,
+        Some(Rc::clone(&globals)),
+        &env,
+        EvalMode::Lazy,
+        &mut Vec::new(),
+    )?;
+    Ok(WarmGlobals {
+        generation,
+        last_used: Instant::now(),
+        globals,
+        lib,
+        pkgs,
+    })
+}
+
+fn evaluate(
+    expression: &str,
+    checkout: &Path,
+    globals: Option<Rc<GlobalsMap>>,
+    env: &FxHashMap<SmolStr, Value>,
+    mode: EvalMode,
+    warnings: &mut Vec<String>,
+) -> Result<(Value, Rc<GlobalsMap>), Error> {
+    let mut builder = snix_eval::Evaluation::builder_impure()
+        .mode(mode)
+        .env(Some(env));
+    match globals {
+        None => {
+            let global_builder = |expression| {
+                check_value_for_errors(
+                    snix_eval::Evaluation::builder_pure()
+                        .build()
+                        .evaluate(expression, None),
+                )
+            };
+            let derivation = global_builder("arg: arg // {out={type=null;outputName=null;};}")?;
+            let placeholder = global_builder("arg: arg")?;
+            builder = builder.enable_import();
+            builder = builder.add_builtins(vec![
+                ("derivation", derivation),
+                ("placeholder", placeholder),
+            ]);
+            builder = builder.io_handle(Box::new(NixpkgsIo::new(checkout)));
+        }
+        Some(globals) => {
+            builder = builder.with_globals(globals);
+            builder = builder.io_handle(Box::new(NixpkgsIo::new(checkout)));
+        }
+    }
+    let evaluator = builder.build();
+    let globals = Rc::clone(&evaluator.globals());
+    let source = evaluator.source_map();
+    let result = evaluator.evaluate(expression, Some(checkout.into()));
+    warnings.extend(
+        result
+            .warnings
+            .iter()
+            .map(|warning| warning.fancy_format_str(&source)),
+    );
+    let result = check_value_for_errors(result)?;
+    Ok((result, globals))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::snix::io::tests::token_in_environment;
+
+    #[tokio::test]
+    async fn workers_do_not_inherit_the_token() {
+        token_in_environment();
+        // The worker's `EVAL_ENV` looks passthrough variables up in its own environment, so
+        // with nothing in it, `builtins.getEnv "TOKEN"` has nothing to find.
+        let output = worker_command(Path::new("/bin/sh"))
+            .args(["-c", "printf %s \"${TOKEN-}\""])
+            .output()
+            .await
+            .expect("The shell runs.");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"");
+    }
+
+    #[test]
+    fn short_traces_are_shown_whole() {
+        let traces = [String::from("trace: a"), String::from("trace: b")];
+        assert_eq!(
+            with_traces("error", &traces),
+            "error\n**Trace**\n```\ntrace: a\ntrace: b\n```"
+        );
+    }
+
+    #[test]
+    fn long_traces_keep_their_end() {
+        let traces = ["a".repeat(ERROR_TRACE_LIMIT), String::from("b")];
+        let shown = with_traces("error", &traces);
+        let length = ERROR_TRACE_LIMIT + 2;
+        assert!(shown.starts_with(&format!(
+            "error\n**Trace** (only the last {ERROR_TRACE_LIMIT} of {length} characters)"
+        )));
+        assert!(shown.ends_with("a\nb\n```"));
+        assert!(shown.chars().count() < ERROR_TRACE_LIMIT + 100);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::Error;
+use crate::args::ARGS;
+use crate::nixpkgs::{CACHE_PATH, nixpkgs_revision};
+use log::{error, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long to wait before building an index again after it first failed. Each failure after
+/// that doubles it, up to `MAX_RETRY_DELAY`.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The last failed build of the index of some revision.
+struct Failure {
+    error: String,
+    delay: Duration,
+    retry_at: SystemTime,
+}
+
+/// Keeps track of building one kind of index in the background. Building one evaluates far too
+/// much to do more than once at a time, or to try again straight after it failed.
+pub(crate) struct IndexBuilds {
+    /// What gets indexed, for logs and error messages.
+    what: &'static str,
+    building: AtomicBool,
+    failures: Mutex<BTreeMap<String, Failure>>,
+}
+
+impl IndexBuilds {
+    pub(crate) const fn new(what: &'static str) -> Self {
+        Self {
+            what,
+            building: AtomicBool::new(false),
+            failures: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Why the last build for `revision` failed, unless it didn't or it's time to try again.
+    pub(crate) fn failure(&self, revision: &str) -> Option<Error> {
+        let failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        let failure = failures
+            .get(revision)
+            .filter(|failure| failure.retry_at > SystemTime::now())?;
+        let retry_at = failure
+            .retry_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        Some(Error::from(format!(
+            "Indexing {} failed: {}\nIt'll be tried again <t:{retry_at}:R>.",
+            self.what, failure.error
+        )))
+    }
+
+    /// Runs `build` for `revision` in the background, unless an index is already being built or
+    /// the last build for `revision` failed too recently.
+    pub(crate) fn start(
+        &'static self,
+        revision: String,
+        build: impl Future<Output = Result<(), Error>> + Send + 'static,
+    ) {
+        if self.failure(&revision).is_some() || self.building.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // Cleared when dropped, so a build that panics doesn't leave it set.
+        let building = Building(&self.building);
+        tokio::spawn(async move {
+            let _building = building;
+            let result = build.await;
+            let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+            let Err(error) = result else {
+                failures.remove(&revision);
+                return;
+            };
+            error!("Failed to index {}: {error}", self.what);
+            let delay = failures
+                .get(&revision)
+                .map_or(FIRST_RETRY_DELAY, |failure| {
+                    (failure.delay * 2).min(MAX_RETRY_DELAY)
+                });
+            let now = SystemTime::now();
+            // Failures long past don't say anything about how long to wait anymore.
+            failures.retain(|_, failure| failure.retry_at + MAX_RETRY_DELAY > now);
+            failures.insert(
+                revision,
+                Failure {
+                    error: error.to_string(),
+                    delay,
+                    retry_at: now + delay,
+                },
+            );
+        });
+    }
+}
+
+struct Building(&'static AtomicBool);
+
+impl Drop for Building {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Where one kind of index is kept once built: on disk for every revision some channel has
+/// checked out, so they survive restarts, and in memory for the most recently asked about one.
+pub(crate) struct IndexCache<T> {
+    /// The directory in `CACHE_PATH` the indexes are written to.
+    directory: &'static str,
+    latest: tokio::sync::Mutex<Option<(String, Arc<T>)>>,
+}
+
+impl<T: Serialize + DeserializeOwned> IndexCache<T> {
+    pub(crate) const fn new(directory: &'static str) -> Self {
+        Self {
+            directory,
+            latest: tokio::sync::Mutex::const_new(None),
+        }
+    }
+
+    fn directory(&self) -> PathBuf {
+        CACHE_PATH.join(self.directory)
+    }
+
+    fn file(&self, revision: &str) -> PathBuf {
+        self.directory().join(format!("{revision}.json"))
+    }
+
+    /// The index for `revision`, from memory or from disk. `None` if it hasn't been built yet.
+    pub(crate) async fn get(&self, revision: &str) -> Option<Arc<T>> {
+        let mut latest = self.latest.lock().await;
+        if let Some((_, cached)) = latest.as_ref().filter(|(indexed, _)| indexed == revision) {
+            return Some(Arc::clone(cached));
+        }
+
+        let contents = tokio::fs::read(self.file(revision)).await.ok()?;
+        let loaded = match serde_json::from_slice::<T>(&contents) {
+            Ok(loaded) => Arc::new(loaded),
+            Err(error) => {
+                warn!(
+                    "Ignoring unreadable index in {} for {revision}: {error}",
+                    self.directory
+                );
+                return None;
+            }
+        };
+        *latest = Some((revision.to_string(), Arc::clone(&loaded)));
+        Some(loaded)
+    }
+
+    /// Keeps `index` as the index for `revision`, both in memory and on disk.
+    pub(crate) async fn store(&self, revision: String, index: T) -> Result<(), Error> {
+        // Only revisions some channel has checked out get asked about, so indexes of any other
+        // revision are just taking up space.
+        let checked_out: Vec<String> = ARGS
+            .channels
+            .iter()
+            .filter_map(|channel| nixpkgs_revision(channel))
+            .collect();
+        tokio::fs::create_dir_all(self.directory()).await?;
+        let mut indexes = tokio::fs::read_dir(self.directory()).await?;
+        while let Some(entry) = indexes.next_entry().await? {
+            let path = entry.path();
+            let indexed = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if !checked_out.contains(&indexed) {
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+        tokio::fs::write(self.file(&revision), serde_json::to_vec(&index)?).await?;
+
+        *self.latest.lock().await = Some((revision, Arc::new(index)));
+        Ok(())
+    }
+}
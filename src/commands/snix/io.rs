@@ -1,23 +1,67 @@
-use crate::nixpkgs::NIXPKGS_PATH;
+use crate::args::ARGS;
 use bytes::Bytes;
+use rustc_hash::FxHashMap;
 use snix_eval::{EvalIO, FileType};
 use std::ffi::{OsStr, OsString};
 use std::io::Read;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
 use std::{fs, io};
 
-pub struct NixpkgsIo;
+/// Environment variables which are never visible to evaluations, whatever the configuration says.
+const SECRET_VARIABLES: &[&str] = &["TOKEN", "GITHUB_TOKEN"];
+
+/// Everything `builtins.getEnv` is allowed to see.
+pub(crate) static EVAL_ENV: LazyLock<FxHashMap<String, String>> = LazyLock::new(|| {
+    visible_env(&ARGS.eval_env, &ARGS.eval_env_passthrough, |name| {
+        std::env::var(name).ok()
+    })
+});
+
+fn visible_env(
+    fake: &[String],
+    passthrough: &[String],
+    lookup: impl Fn(&str) -> Option<String>,
+) -> FxHashMap<String, String> {
+    let fake = fake
+        .iter()
+        .filter_map(|variable| variable.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+    let passed = passthrough
+        .iter()
+        .filter_map(|name| Some((name.clone(), lookup(name)?)));
+    fake.chain(passed)
+        .filter(|(name, _)| !SECRET_VARIABLES.contains(&name.as_str()))
+        .collect()
+}
+
+/// Gives evaluations access to one nixpkgs checkout, and nothing outside of it.
+pub struct NixpkgsIo {
+    root: PathBuf,
+    env: &'static FxHashMap<String, String>,
+}
 
 impl NixpkgsIo {
-    fn ensure_inside(path: &Path) -> io::Result<PathBuf> {
+    pub(crate) fn new(root: &Path) -> Self {
+        Self::with_env(root, &EVAL_ENV)
+    }
+
+    /// Like `new`, but with `env` standing in for `EVAL_ENV`.
+    pub(crate) fn with_env(root: &Path, env: &'static FxHashMap<String, String>) -> Self {
+        // Paths are compared once canonicalized, so the root has to be too.
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        Self { root, env }
+    }
+
+    fn ensure_inside(&self, path: &Path) -> io::Result<PathBuf> {
         let abs = if path.is_absolute() {
             path.to_path_buf()
         } else {
-            NIXPKGS_PATH.join(path)
+            self.root.join(path)
         };
         let canon = abs.canonicalize()?;
-        if !canon.starts_with(&*NIXPKGS_PATH) {
+        if !canon.starts_with(&self.root) {
             return Err(io::Error::new(
                 io::ErrorKind::PermissionDenied,
                 format!(
@@ -32,7 +76,7 @@ impl NixpkgsIo {
 
 impl EvalIO for NixpkgsIo {
     fn path_exists(&self, path: &Path) -> io::Result<bool> {
-        let exists = match Self::ensure_inside(path) {
+        let exists = match self.ensure_inside(path) {
             Ok(path) => path.exists(),
             Err(_) => false,
         };
@@ -40,12 +84,12 @@ impl EvalIO for NixpkgsIo {
     }
 
     fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
-        let path = Self::ensure_inside(path)?;
+        let path = self.ensure_inside(path)?;
         Ok(Box::new(fs::File::open(path)?))
     }
 
     fn file_type(&self, path: &Path) -> io::Result<FileType> {
-        let path = Self::ensure_inside(path)?;
+        let path = self.ensure_inside(path)?;
         let meta = fs::metadata(path)?;
         if meta.is_file() {
             Ok(FileType::Regular)
@@ -57,7 +101,7 @@ impl EvalIO for NixpkgsIo {
     }
 
     fn read_dir(&self, path: &Path) -> io::Result<Vec<(Bytes, FileType)>> {
-        let path = Self::ensure_inside(path)?;
+        let path = self.ensure_inside(path)?;
         let mut out = Vec::new();
         for entry in fs::read_dir(path)? {
             let entry = entry?;
@@ -76,10 +120,99 @@ impl EvalIO for NixpkgsIo {
     }
 
     fn import_path(&self, path: &Path) -> io::Result<PathBuf> {
-        Self::ensure_inside(path)
+        self.ensure_inside(path)
     }
 
     fn get_env(&self, key: &OsStr) -> Option<OsString> {
-        std::env::var_os(key)
+        key.to_str()
+            .and_then(|key| self.env.get(key))
+            .map(OsString::from)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use snix_eval::Evaluation;
+    use std::sync::Once;
+
+    const TOKEN: &str = "super secret discord token";
+
+    /// Puts a token in the process environment the way the bot is usually run, and returns
+    /// what evaluations would be allowed to see if it were configured to pass `TOKEN` through.
+    pub(crate) fn token_in_environment() -> &'static FxHashMap<String, String> {
+        static SET: Once = Once::new();
+        static ENV: LazyLock<FxHashMap<String, String>> = LazyLock::new(|| {
+            visible_env(
+                &[String::from("HOME=/homeless-shelter")],
+                &[String::from("TOKEN")],
+                |name| std::env::var(name).ok(),
+            )
+        });
+        // SAFETY: this is the only place tests change the environment, and it only happens once.
+        SET.call_once(|| unsafe { std::env::set_var("TOKEN", TOKEN) });
+        &ENV
+    }
+
+    fn real_env(name: &str) -> Option<String> {
+        match name {
+            "TOKEN" => Some(String::from("super secret discord token")),
+            "EDITOR" => Some(String::from("vim")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn fake_variables_are_visible() {
+        let env = visible_env(
+            &[
+                String::from("HOME=/homeless-shelter"),
+                String::from("NIX_PATH="),
+            ],
+            &[],
+            real_env,
+        );
+        assert_eq!(
+            env.get("HOME").map(String::as_str),
+            Some("/homeless-shelter")
+        );
+        assert_eq!(env.get("NIX_PATH").map(String::as_str), Some(""));
+        assert_eq!(env.get("EDITOR"), None);
+    }
+
+    #[test]
+    fn only_allowed_variables_pass_through() {
+        let env = visible_env(&[], &[String::from("EDITOR")], real_env);
+        assert_eq!(env.get("EDITOR").map(String::as_str), Some("vim"));
+        assert_eq!(env.len(), 1);
+    }
+
+    #[test]
+    fn get_env_does_not_see_the_token() {
+        let env = token_in_environment();
+        let checkout = tempfile::tempdir().expect("A temporary directory can be created.");
+        let result = Evaluation::builder_impure()
+            .io_handle(Box::new(NixpkgsIo::with_env(checkout.path(), env)))
+            .build()
+            .evaluate("builtins.getEnv \"TOKEN\"", None);
+        let value = result.value.expect("getEnv evaluates.");
+        assert_eq!(
+            value.to_str().expect("getEnv returns a string").as_bytes(),
+            b""
+        );
+    }
+
+    #[test]
+    fn token_is_never_visible() {
+        let env = visible_env(
+            &[String::from("TOKEN=placeholder")],
+            &[String::from("TOKEN")],
+            real_env,
+        );
+        assert_eq!(env.get("TOKEN"), None);
+        assert!(
+            env.values()
+                .all(|value| !value.contains("super secret discord token"))
+        );
     }
 }
@@ -0,0 +1,239 @@
+use crate::Error;
+use crate::args::ARGS;
+use crate::commands::snix;
+use crate::commands::snix::autocomplete_channel;
+use crate::commands::snix::evaluator::{
+    EvalOptions, Mode, Output, Stopped, evaluate_in_background,
+};
+use crate::commands::snix::indexing::{IndexBuilds, IndexCache};
+use crate::commands::snix::maintainer::{NAME_FUNCTIONS, autocomplete_maintainer};
+use crate::nixpkgs::{
+    channel_checkout, default_channel, nixpkgs_revision, pinned_checkout, unavailable,
+};
+use log::{info, warn};
+use poise::serenity_prelude::{Color, CreateEmbed};
+use poise::{Context, CreateReply, command};
+use rustc_hash::FxHashMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::time::Duration;
+
+/// Which packages and teams list each maintainer, by their name in `lib.maintainers`.
+#[derive(Default, Serialize, Deserialize)]
+struct MaintainedIndex {
+    packages: FxHashMap<String, Vec<String>>,
+    teams: FxHashMap<String, Vec<String>>,
+}
+
+static INDEXES: IndexCache<MaintainedIndex> = IndexCache::new("maintained-by");
+static BUILDS: IndexBuilds = IndexBuilds::new("who maintains what");
+
+/// How many of the attributes at the top of `pkgs` to walk in one evaluation. If any of them
+/// fails in a way `tryEval` can't catch, like an `abort`, each gets walked on its own instead, so
+/// only the package sets that fail are left out. Running out of time or memory fails the whole
+/// index instead, as each on its own would only be stopped again, and take far longer to be.
+const ATTRIBUTES_PER_EVALUATION: usize = 500;
+
+const INDEX_FUNCTIONS: &str = r#"
+  attempt = value: let result = builtins.tryEval value; in if result.success then result.value else null;
+  entriesFor = path: maintainers: map (maintainer: {
+    name = nameOf maintainer;
+    inherit path;
+  }) maintainers;
+  walk = path: set: builtins.concatLists (lib.mapAttrsToList (name: value:
+    let
+      attrPath = path ++ [name];
+      package = attempt value;
+      maintainers = attempt (package.meta.maintainers or []);
+    in
+      if !(builtins.isAttrs package)
+      then []
+      else if (package.type or null) == "derivation"
+      then entriesFor (lib.concatStringsSep "." attrPath) (if builtins.isList maintainers then maintainers else [])
+      else if package.recurseForDerivations or false
+      then walk attrPath package
+      else []) set);
+  group = entries: builtins.mapAttrs (_: map (entry: entry.path))
+    (lib.groupBy (entry: entry.name) (builtins.filter (entry: entry.name != null) entries));
+"#;
+
+/// Lists who maintains the packages under the attributes of `pkgs` called `attributes`.
+fn packages_expression(attributes: &[String]) -> String {
+    let attributes: Vec<String> = attributes
+        .iter()
+        .map(|attribute| snix::nix_string_literal(attribute))
+        .collect();
+    format!(
+        "let {NAME_FUNCTIONS} {INDEX_FUNCTIONS} in group (walk [] (lib.getAttrs [ {} ] pkgs))",
+        attributes.join(" ")
+    )
+}
+
+fn teams_expression() -> String {
+    format!(
+        "let {NAME_FUNCTIONS} {INDEX_FUNCTIONS} in group (builtins.concatLists (lib.mapAttrsToList (team: value: entriesFor team (value.members or [])) lib.teams))"
+    )
+}
+
+/// How many packages or teams to list on each page.
+const PAGE_LENGTH: usize = 40;
+
+async fn evaluate_index<T: DeserializeOwned>(
+    expression: String,
+    checkout: &Path,
+) -> Result<T, Error> {
+    let options = EvalOptions {
+        mode: Mode::Strict,
+        output: Output::Json,
+        timeout: Duration::from_secs(ARGS.index_timeout),
+        checkout: checkout.to_path_buf(),
+    };
+    let output = evaluate_in_background(expression, options).await?;
+    Ok(serde_json::from_str(&output.value)?)
+}
+
+async fn build_index(channel: &'static str, revision: String) -> Result<(), Error> {
+    // Evaluated in a checkout of its own, as building the index takes far too long to hold up
+    // updating the channel's.
+    let pinned = pinned_checkout(&revision).await?;
+    let checkout = pinned.path();
+    info!("Indexing who maintains what in nixpkgs {revision}.");
+    let mut index = MaintainedIndex {
+        packages: FxHashMap::default(),
+        teams: evaluate_index(teams_expression(), checkout).await?,
+    };
+    let attributes: Vec<String> =
+        evaluate_index(String::from("builtins.attrNames pkgs"), checkout).await?;
+    let mut left_out: usize = 0;
+    for chunk in attributes.chunks(ATTRIBUTES_PER_EVALUATION) {
+        let packages: FxHashMap<String, Vec<String>> =
+            match evaluate_index(packages_expression(chunk), checkout).await {
+                Ok(packages) => packages,
+                Err(error) if error.is::<Stopped>() => return Err(error),
+                Err(_) => {
+                    let mut packages: FxHashMap<String, Vec<String>> = FxHashMap::default();
+                    for attribute in chunk {
+                        let expression = packages_expression(std::slice::from_ref(attribute));
+                        match evaluate_index(expression, checkout).await {
+                            Ok(found) => merge(&mut packages, found),
+                            Err(error) if error.is::<Stopped>() => return Err(error),
+                            Err(error) => {
+                                warn!(
+                                    "Leaving pkgs.{attribute} out of the maintainer index: {error}"
+                                );
+                                left_out += 1;
+                            }
+                        }
+                    }
+                    packages
+                }
+            };
+        merge(&mut index.packages, packages);
+    }
+    if nixpkgs_revision(channel).as_deref() != Some(revision.as_str()) {
+        return Err(Error::from(format!(
+            "nixpkgs moved away from {revision} while it was being indexed."
+        )));
+    }
+
+    if left_out > 0 {
+        warn!(
+            "Left {left_out} attributes of pkgs that failed to evaluate out of the maintainer index."
+        );
+    }
+    info!("Finished indexing who maintains what in nixpkgs {revision}.");
+    INDEXES.store(revision, index).await
+}
+
+/// Adds the paths `from` lists for each maintainer to those `into` does.
+fn merge(into: &mut FxHashMap<String, Vec<String>>, from: FxHashMap<String, Vec<String>>) {
+    for (name, paths) in from {
+        into.entry(name).or_default().extend(paths);
+    }
+}
+
+fn pages(name: &str, packages: &[String], teams: &[String]) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    if !teams.is_empty() {
+        lines.push(format!("**Teams** ({})", teams.len()));
+        lines.extend(teams.iter().map(|team| format!("`{team}`")));
+    }
+    if !packages.is_empty() {
+        lines.push(format!("**Packages** ({})", packages.len()));
+        lines.extend(packages.iter().map(|package| format!("`pkgs.{package}`")));
+    }
+
+    let page_count = lines.len().div_ceil(PAGE_LENGTH);
+    lines
+        .chunks(PAGE_LENGTH)
+        .enumerate()
+        .map(|(page, lines)| {
+            format!(
+                "### Maintained by {name}\n{}\n-# Page {} of {page_count}",
+                lines.join("\n"),
+                page + 1
+            )
+        })
+        .collect()
+}
+
+#[command(
+    slash_command,
+    rename = "maintained-by",
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub(crate) async fn maintained_by(
+    ctx: Context<'_, (), Error>,
+    #[autocomplete = "autocomplete_maintainer"]
+    #[description = "Maintainer Name/Handle"]
+    name: String,
+    #[autocomplete = "autocomplete_channel"]
+    #[description = "Nixpkgs channel to look in"]
+    channel: Option<String>,
+) -> Result<(), Error> {
+    channel_checkout(channel.as_deref())?;
+    // Validated above, so it's one of the configured channels.
+    let channel: &'static str = ARGS
+        .channels
+        .iter()
+        .find(|configured| Some(configured.as_str()) == channel.as_deref())
+        .map_or_else(default_channel, String::as_str);
+    let revision = nixpkgs_revision(channel).ok_or_else(unavailable)?;
+    let Some(index) = INDEXES.get(&revision).await else {
+        if let Some(error) = BUILDS.failure(&revision) {
+            return Err(error);
+        }
+        BUILDS.start(revision.clone(), build_index(channel, revision));
+        ctx.say(
+            "Nixpkgs is still being indexed for who maintains what, which takes a few minutes. Try again later.",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let packages = index.packages.get(&name).map_or(&[][..], Vec::as_slice);
+    let teams = index.teams.get(&name).map_or(&[][..], Vec::as_slice);
+    if packages.is_empty() && teams.is_empty() {
+        return Err(Error::from(format!(
+            "`{name}` isn't listed as a maintainer of any package or team."
+        )));
+    }
+
+    let pages = pages(&name, packages, teams);
+    match pages.as_slice() {
+        [page] => {
+            let embed = CreateEmbed::new()
+                .description(page)
+                .color(Color::from((35, 127, 235)));
+            ctx.send(CreateReply::default().embed(embed).reply(true))
+                .await?;
+        }
+        pages => {
+            let pages: Vec<&str> = pages.iter().map(String::as_str).collect();
+            poise::builtins::paginate(ctx, &pages).await?;
+        }
+    }
+    Ok(())
+}
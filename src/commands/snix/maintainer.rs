@@ -1,56 +1,168 @@
 use crate::Error;
+use crate::args::ARGS;
 use crate::commands::snix;
-use crate::commands::snix::io::NixpkgsIo;
-use crate::nixpkgs::{NIXPKGS_PATH, NIXPKGS_REPO};
-use openapi_github::apis::configuration::Configuration;
-use openapi_github::apis::users_api::users_slash_get_by_username;
-use openapi_github::models::UsersGetAuthenticated200Response;
+use crate::commands::snix::evaluator::{
+    EvalOptions, Mode, Output, evaluate_expression, evaluate_in_background,
+};
+use crate::commands::snix::{AUTOCOMPLETE_CHOICES, AUTOCOMPLETE_NAME_LIMIT, autocomplete_channel};
+use crate::github;
+use crate::nixpkgs::{default_channel, nixpkgs_revision, pinned_checkout, unavailable};
+use log::{error, info};
 use poise::serenity_prelude::{AutocompleteChoice, Color, CreateAutocompleteResponse, CreateEmbed};
 use poise::{Context, CreateReply, command};
-use snix_eval::{Evaluation, EvaluationResult, Value};
-use std::path::PathBuf;
+use serde::Deserialize;
+use std::sync::{Arc, LazyLock};
+use tokio::sync::Mutex;
+use tokio::time::Duration;
 
-#[allow(clippy::unused_async)]
-async fn autocomplete_maintainer(
+/// What a maintainer can be searched by, from their entry in `lib.maintainers`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MaintainerEntry {
+    key: String,
+    name: Option<String>,
+    github: Option<String>,
+    github_id: Option<String>,
+    email: Option<String>,
+    matrix: Option<String>,
+}
+
+impl MaintainerEntry {
+    /// How well the maintainer matches a lowercase `query`, by whichever field matches best.
+    fn score(&self, query: &str) -> Option<u32> {
+        [
+            Some(&self.key),
+            self.name.as_ref(),
+            self.github.as_ref(),
+            self.github_id.as_ref(),
+            self.email.as_ref(),
+            self.matrix.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|field| match_score(&field.to_lowercase(), query))
+        .max()
+    }
+
+    /// "Full Name (@github)", falling back to the attribute name for whatever's missing.
+    fn label(&self) -> String {
+        let name = self.name.as_ref().unwrap_or(&self.key);
+        let label = match &self.github {
+            Some(github) => format!("{name} (@{github})"),
+            None => name.clone(),
+        };
+        label.chars().take(AUTOCOMPLETE_NAME_LIMIT).collect()
+    }
+}
+
+/// Exact matches rank above prefixes, prefixes above substrings, and substrings above the
+/// query's characters merely appearing in order.
+fn match_score(candidate: &str, query: &str) -> Option<u32> {
+    if candidate == query {
+        Some(4)
+    } else if candidate.starts_with(query) {
+        Some(3)
+    } else if candidate.contains(query) {
+        Some(2)
+    } else {
+        let mut candidate = candidate.chars();
+        query
+            .chars()
+            .all(|wanted| candidate.any(|character| character == wanted))
+            .then_some(1)
+    }
+}
+
+/// Every maintainer in the checkout, so autocompletion doesn't have to evaluate
+/// `lib.maintainers` on every keystroke. Empty until the first index has been built.
+static MAINTAINERS: LazyLock<Mutex<Arc<Vec<MaintainerEntry>>>> =
+    LazyLock::new(|| Mutex::new(Arc::new(Vec::new())));
+
+// Packages and teams carry copies of the attrsets in `lib.maintainers` rather than their names,
+// so maintainers are matched back up to their names by whichever handle they have. `nameOf` is
+// `null` for anyone who isn't in `lib.maintainers`.
+pub(crate) const NAME_FUNCTIONS: &str = r#"
+  handle = maintainer: maintainer.github or maintainer.email or maintainer.name or null;
+  names = builtins.listToAttrs (lib.mapAttrsToList (name: maintainer: {
+    name = toString (handle maintainer);
+    value = name;
+  }) lib.maintainers);
+  nameOf = maintainer:
+    if handle maintainer == null
+    then null
+    else names.${toString (handle maintainer)} or null;
+"#;
+
+/// Turns the maintainer called `key` in `lib.maintainers` into a `MaintainerEntry`.
+const ENTRY_FUNCTION: &str = r#"
+key: maintainer: {
+  inherit key;
+  name = maintainer.name or null;
+  github = maintainer.github or null;
+  githubId = if maintainer ? githubId then toString maintainer.githubId else null;
+  email = maintainer.email or null;
+  matrix = maintainer.matrix or null;
+}
+"#;
+
+fn maintainers_expression() -> String {
+    format!("lib.mapAttrsToList ({ENTRY_FUNCTION}) lib.maintainers")
+}
+
+fn maintainer_expression(key: &str) -> String {
+    format!(
+        "({ENTRY_FUNCTION}) {} lib.maintainers{}",
+        snix::nix_string_literal(key),
+        snix::attribute_selector(key)
+    )
+}
+
+/// Reads the maintainer list from the checkout into `MAINTAINERS`. Has to be called whenever the
+/// checkout moves.
+pub(crate) async fn rebuild_maintainer_index() {
+    let maintainers = async {
+        let revision = nixpkgs_revision(default_channel()).ok_or_else(unavailable)?;
+        let checkout = pinned_checkout(&revision).await?;
+        let options = EvalOptions {
+            mode: Mode::Strict,
+            output: Output::Json,
+            timeout: Duration::from_secs(ARGS.index_timeout),
+            checkout: checkout.path().to_path_buf(),
+        };
+        let output = evaluate_in_background(maintainers_expression(), options).await?;
+        Ok::<_, Error>(serde_json::from_str::<Vec<MaintainerEntry>>(&output.value)?)
+    }
+    .await;
+    match maintainers {
+        Ok(maintainers) => {
+            info!("Indexed {} maintainers.", maintainers.len());
+            *MAINTAINERS.lock().await = Arc::new(maintainers);
+        }
+        Err(error) => error!("Failed to index maintainers: {error}"),
+    }
+}
+
+pub(crate) async fn autocomplete_maintainer(
     _ctx: Context<'_, (), Error>,
     partial: &str,
 ) -> CreateAutocompleteResponse {
-    let mode = snix_eval::EvalMode::Strict;
-    let builder = snix_eval::Evaluation::builder_pure()
-        .mode(mode)
-        .enable_import()
-        .enable_impure(Some(Box::new(NixpkgsIo)));
-    let evaluation = builder.build();
-    let result: EvaluationResult = Evaluation::evaluate(
-        evaluation,
-        "(import ./lib).maintainers",
-        Some(NIXPKGS_PATH.as_path().into()),
-    );
-
-    let mut maintainers: Vec<String> = match snix::check_value_for_errors(result) {
-        Ok(Value::Attrs(attrs)) => attrs
-            .keys()
-            .map(|maintainer| {
-                let mut maintainer: &str = &maintainer.to_string();
-
-                if maintainer.starts_with('"') {
-                    maintainer = &maintainer[1..];
-                }
-                if maintainer.ends_with('"') {
-                    maintainer = &maintainer[..maintainer.len() - 1];
-                }
-                maintainer.to_string()
-            })
-            .collect(),
-        _ => Vec::new(),
-    };
+    let maintainers = Arc::clone(&*MAINTAINERS.lock().await);
 
-    let maintainers: Vec<String> = maintainers
-        .iter_mut()
-        .filter(|maintainer| maintainer.starts_with(partial))
-        .map(|name| (*name).to_string())
-        .collect::<Vec<String>>();
-    let choices = maintainers.iter().map(AutocompleteChoice::from).collect();
+    let query = partial.trim().to_lowercase();
+    let mut matches: Vec<(u32, &MaintainerEntry)> = maintainers
+        .iter()
+        .filter_map(|maintainer| Some((maintainer.score(&query)?, maintainer)))
+        .collect();
+    matches.sort_by(|(score, maintainer), (other_score, other)| {
+        other_score
+            .cmp(score)
+            .then_with(|| maintainer.key.cmp(&other.key))
+    });
+    let choices = matches
+        .into_iter()
+        .take(AUTOCOMPLETE_CHOICES)
+        .map(|(_, maintainer)| AutocompleteChoice::new(maintainer.label(), maintainer.key.clone()))
+        .collect();
 
     let mut autocomplete_response = CreateAutocompleteResponse::new();
     autocomplete_response = autocomplete_response.set_choices(choices);
@@ -67,61 +179,37 @@ pub(crate) async fn maintainer(
     #[autocomplete = "autocomplete_maintainer"]
     #[description = "Maintainer Name/Handle"]
     name: String,
+    #[autocomplete = "autocomplete_channel"]
+    #[description = "Nixpkgs channel to look in"]
+    channel: Option<String>,
 ) -> Result<(), Error> {
-    let nixpkgs_repo = NIXPKGS_REPO
-        .try_lock()
-        .map_err(|_| "The nixpkgs repo is currently in use elsewhere. Try again later.")?;
-    // We're gonna use this later for the embed image
-    let mut github_username: Option<String> = None;
-    let mut embed = nixpkgs_repo
-        .as_ref()
-        .map(|nixpkgs| {
-            let nixpkgs_root = nixpkgs.path().parent().unwrap();
-            let maintainer_expression =
-                format!("(import ./maintainers/maintainer-list.nix).{name}");
-            let mode = snix_eval::EvalMode::Strict;
-            let builder = snix_eval::Evaluation::builder_pure()
-                .mode(mode)
-                .enable_impure(Some(Box::new(NixpkgsIo)));
-            let evaluation = builder.build();
-            let result: EvaluationResult = Evaluation::evaluate(
-                evaluation,
-                maintainer_expression,
-                Some(PathBuf::from(nixpkgs_root)),
-            );
-            let maintainer = snix::check_value_for_errors(result)?
-                .to_attrs()
-                .map_err(|_| "Expression wasn't an attrset!")?;
-
-            // Set the github username for later use
-            if let Some(username_value) = maintainer.select("github") {
-                let quoted_username = format!("{username_value}");
-                let username = &quoted_username[1..&quoted_username.len() - 1];
-                github_username = Some(String::from(username));
-            }
-
-            let mut embed: CreateEmbed = CreateEmbed::new()
-                .title("Maintainer Info")
-                .color(Color::from((35, 127, 235)));
-            embed = snix::add_embed_field(embed, "Name", maintainer.select("name"));
-            embed = snix::add_embed_field(embed, "Email", maintainer.select("email"));
-            embed = snix::add_embed_field(embed, "GitHub Username", maintainer.select("github"));
-            embed = snix::add_embed_field(embed, "GitHub ID", maintainer.select("githubId"));
-            embed = snix::add_embed_field(embed, "Matrix", maintainer.select("matrix"));
-
-            Ok::<CreateEmbed, Error>(embed)
-        })
-        .ok_or("The nixpkgs repo has not been set up. Try again later.")??;
-
-    if let Some(username) = github_username {
-        let maintainer_github_account =
-            users_slash_get_by_username(&Configuration::default(), &username)
-                .await
-                .map_err(|_| "Couldn't fetch github user")?;
-        let avatar: String = match maintainer_github_account {
-            UsersGetAuthenticated200Response::PrivateUser(user) => user.avatar_url,
-            UsersGetAuthenticated200Response::PublicUser(user) => user.avatar_url,
-        };
+    // Fetching the maintainer's avatar from GitHub can take longer than Discord waits for a reply.
+    ctx.defer().await?;
+    let options = EvalOptions::new(
+        ctx.guild_id(),
+        channel.as_deref(),
+        Some(Mode::Strict),
+        Some(Output::Json),
+        None,
+    )?;
+    let output = evaluate_expression(maintainer_expression(&name), Vec::new(), options).await?;
+    let maintainer: MaintainerEntry = serde_json::from_str(&output.value)?;
+
+    let mut embed: CreateEmbed = CreateEmbed::new()
+        .title("Maintainer Info")
+        .color(Color::from((35, 127, 235)));
+    embed = snix::add_embed_text_field(embed, "Name", maintainer.name.as_deref());
+    embed = snix::add_embed_text_field(embed, "Email", maintainer.email.as_deref());
+    embed = snix::add_embed_text_field(embed, "GitHub Username", maintainer.github.as_deref());
+    embed = snix::add_embed_text_field(embed, "GitHub ID", maintainer.github_id.as_deref());
+    embed = snix::add_embed_text_field(embed, "Matrix", maintainer.matrix.as_deref());
+
+    // The embed is still worth sending without a picture if GitHub doesn't answer.
+    let avatar = match &maintainer.github {
+        Some(username) => github::avatar_url(username).await,
+        None => None,
+    };
+    if let Some(avatar) = avatar {
         embed = embed.thumbnail(avatar);
     }
 
@@ -131,6 +219,11 @@ pub(crate) async fn maintainer(
 }
 
 pub fn format_field_value(string: &str) -> String {
+    format!("`{}`", unquote(string))
+}
+
+/// Strips the quotes Nix puts around strings when displaying them.
+fn unquote(string: &str) -> &str {
     let mut inner: &str = string;
 
     if inner.starts_with('"') {
@@ -139,6 +232,33 @@ pub fn format_field_value(string: &str) -> String {
     if inner.ends_with('"') {
         inner = &inner[..inner.len() - 1];
     }
+    inner
+}
 
-    format!("`{inner}`")
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_matches_rank_highest() {
+        assert_eq!(match_score("alice", "alice"), Some(4));
+        assert_eq!(match_score("alicex", "alice"), Some(3));
+        assert_eq!(match_score("xalicex", "alice"), Some(2));
+        assert_eq!(match_score("a-l-i-c-e", "alice"), Some(1));
+    }
+
+    #[test]
+    fn characters_have_to_appear_in_order() {
+        assert_eq!(match_score("ecila", "alice"), None);
+        assert_eq!(match_score("alic", "alice"), None);
+        assert_eq!(match_score("aalice", "aa"), Some(3));
+        assert_eq!(match_score("abab", "aab"), Some(1));
+        assert_eq!(match_score("ab", "aab"), None);
+    }
+
+    #[test]
+    fn empty_queries_match_everything() {
+        assert_eq!(match_score("alice", ""), Some(3));
+        assert_eq!(match_score("", ""), Some(4));
+    }
 }
@@ -1,11 +1,23 @@
 use crate::Error;
-use poise::serenity_prelude::CreateEmbed;
+use crate::args::ARGS;
+use poise::Context;
+use poise::serenity_prelude::{
+    AutocompleteChoice, CommandDataOptionValue, CreateAutocompleteResponse, CreateEmbed,
+};
 use snix_eval::{EvaluationResult, Value};
 use std::iter::Map;
 
+pub(crate) mod doc;
+pub(crate) mod evaluator;
+mod indexing;
 mod io;
+pub(crate) mod maintained_by;
 pub(crate) mod maintainer;
+pub(crate) mod option;
+pub(crate) mod package;
 pub(crate) mod repl;
+pub(crate) mod session;
+pub(crate) mod team;
 
 pub(crate) fn check_value_for_errors(wrapped_result: EvaluationResult) -> Result<Value, Error> {
     match (wrapped_result.value, wrapped_result.errors.as_slice()) {
@@ -22,17 +34,134 @@ pub(crate) fn check_value_for_errors(wrapped_result: EvaluationResult) -> Result
     }
 }
 
-pub(crate) fn add_embed_field(
+/// Discord refuses embed descriptions longer than this.
+pub(crate) const EMBED_DESCRIPTION_LIMIT: usize = 4096;
+
+/// Discord refuses embed field values longer than this.
+pub(crate) const EMBED_FIELD_LIMIT: usize = 1024;
+
+/// Discord refuses embed footers longer than this.
+pub(crate) const EMBED_FOOTER_LIMIT: usize = 2048;
+
+/// Discord shows at most this many autocomplete choices.
+pub(crate) const AUTOCOMPLETE_CHOICES: usize = 25;
+
+/// Discord refuses autocomplete choices with names longer than this.
+pub(crate) const AUTOCOMPLETE_NAME_LIMIT: usize = 100;
+
+/// Cuts `text` down to `limit` characters, ending in an ellipsis if anything was cut.
+pub(crate) fn truncate(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        text.to_string()
+    } else {
+        text.chars().take(limit - 1).collect::<String>() + "…"
+    }
+}
+
+/// Puts `code` in a code block highlighted as `language`, cut down to fit in an embed field.
+pub(crate) fn code_block(code: &str, language: &str) -> String {
+    let fences = "```\n\n```".len() + language.len();
+    format!(
+        "```{language}\n{}\n```",
+        truncate(code, EMBED_FIELD_LIMIT - fences)
+    )
+}
+
+pub(crate) fn add_embed_text_field(
     mut embed: CreateEmbed,
     name: &str,
-    value: Option<&Value>,
+    value: Option<&str>,
 ) -> CreateEmbed {
     if let Some(value) = value {
-        embed = embed.field(
-            name,
-            maintainer::format_field_value(&format!("{value}")),
-            false,
-        );
+        let mut value = maintainer::format_field_value(value);
+        if value.chars().count() > EMBED_FIELD_LIMIT {
+            value = value
+                .chars()
+                .take(EMBED_FIELD_LIMIT - 4)
+                .collect::<String>()
+                + "…`";
+        }
+        embed = embed.field(name, value, false);
     }
     embed
 }
+
+/// Quotes a string as a Nix string literal, so nothing in it is read as Nix syntax.
+pub(crate) fn nix_string_literal(string: &str) -> String {
+    let escaped = string
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{escaped}\"")
+}
+
+/// Turns a dotted attribute path into selectors with every component quoted, so
+/// `python3Packages.requests` becomes `."python3Packages"."requests"`.
+pub(crate) fn attribute_selector(path: &str) -> String {
+    path.split('.')
+        .filter(|component| !component.is_empty())
+        .map(|component| format!(".{}", nix_string_literal(component)))
+        .collect()
+}
+
+#[allow(clippy::unused_async)]
+pub(crate) async fn autocomplete_channel(
+    _ctx: Context<'_, (), Error>,
+    partial: &str,
+) -> CreateAutocompleteResponse {
+    let choices = ARGS
+        .channels
+        .iter()
+        .filter(|channel| channel.starts_with(partial))
+        .map(AutocompleteChoice::from)
+        .collect();
+    CreateAutocompleteResponse::new().set_choices(choices)
+}
+
+/// The channel already chosen for the command being autocompleted, if any.
+pub(crate) fn chosen_channel(ctx: Context<'_, (), Error>) -> Option<String> {
+    let Context::Application(ctx) = ctx else {
+        return None;
+    };
+    ctx.interaction
+        .data
+        .options
+        .iter()
+        .find(|option| option.name == "channel")
+        .and_then(|option| match &option.value {
+            CommandDataOptionValue::String(channel) => Some(channel.clone()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_at_the_limit_is_left_alone() {
+        assert_eq!(truncate("abc", 3), "abc");
+        assert_eq!(truncate("äöü", 3), "äöü");
+        assert_eq!(truncate("", 1), "");
+    }
+
+    #[test]
+    fn text_over_the_limit_is_cut_to_it() {
+        assert_eq!(truncate("abcd", 3), "ab…");
+        assert_eq!(truncate("äöüß", 3), "äö…");
+        assert_eq!(truncate("abcd", 3).chars().count(), 3);
+    }
+
+    #[test]
+    fn code_blocks_fit_in_a_field() {
+        let fences = "```nix\n\n```".len();
+        let fits = "x".repeat(EMBED_FIELD_LIMIT - fences);
+        let block = code_block(&fits, "nix");
+        assert_eq!(block.chars().count(), EMBED_FIELD_LIMIT);
+        assert!(!block.contains('…'));
+
+        let block = code_block(&format!("{fits}x"), "nix");
+        assert_eq!(block.chars().count(), EMBED_FIELD_LIMIT);
+        assert!(block.ends_with("x…\n```"));
+    }
+}
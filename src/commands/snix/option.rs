@@ -0,0 +1,279 @@
+use crate::Error;
+use crate::args::ARGS;
+use crate::commands::snix::evaluator::{EvalOptions, Mode, Output, SYSTEM, evaluate_in_background};
+use crate::commands::snix::indexing::{IndexBuilds, IndexCache};
+use crate::commands::snix::package::relative_position;
+use crate::commands::snix::{
+    AUTOCOMPLETE_CHOICES, EMBED_DESCRIPTION_LIMIT, EMBED_FIELD_LIMIT, EMBED_FOOTER_LIMIT,
+    autocomplete_channel, chosen_channel, code_block, truncate,
+};
+use crate::nixpkgs::{
+    channel_checkout, default_channel, nixpkgs_revision, pinned_checkout, unavailable,
+};
+use log::info;
+use poise::serenity_prelude::{
+    AutocompleteChoice, Color, CreateAutocompleteResponse, CreateEmbed, CreateEmbedFooter,
+};
+use poise::{Context, CreateReply, command};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, LazyLock};
+use tokio::time::Duration;
+
+/// What the `NixOS` manual would say about an option.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OptionInfo {
+    name: String,
+    r#type: Option<String>,
+    default: Option<String>,
+    example: Option<String>,
+    description: Option<String>,
+    declarations: Vec<String>,
+    read_only: bool,
+}
+
+/// Every option of a revision, by name.
+static INDEXES: IndexCache<BTreeMap<String, OptionInfo>> = IndexCache::new("options");
+static BUILDS: IndexBuilds = IndexBuilds::new("the NixOS options");
+
+/// `MyST` roles like {option}`services.foo.enable`, which Discord would show as is.
+static MYST_ROLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{[a-z]+\}`").expect("The role pattern is valid."));
+
+// Lists options the same way the NixOS manual does, except that anything which fails to
+// evaluate is left out rather than failing the whole index. Declarations are absolute paths into
+// the checkout, and are made relative once the index is back.
+fn index_expression() -> String {
+    format!(
+        r#"
+let
+  nixos = import ./nixos/lib/eval-config.nix {{
+    inherit lib pkgs;
+    system = "{SYSTEM}";
+    modules = [];
+  }};
+  attempt = value: let result = builtins.tryEval value; in if result.success then result.value else null;
+  render = value: attempt (lib.options.renderOptionValue value).text;
+  text = value: if builtins.isAttrs value then value.text or null else value;
+  document = options: builtins.concatMap (option:
+    let
+      visible = option.visible or true;
+      subOptions = attempt (option.type.getSubOptions option.loc);
+    in
+      if (option.internal or false) || visible == false
+      then []
+      else [{{
+        name = lib.showOption option.loc;
+        type = attempt (option.type.description or null);
+        default = if option ? defaultText || option ? default then render (option.defaultText or option.default) else null;
+        example = if option ? example then render option.example else null;
+        description = attempt (text (option.description or null));
+        declarations = map toString (option.declarations or []);
+        readOnly = option.readOnly or false;
+      }}] ++ (if builtins.isAttrs subOptions && visible != "shallow" then document subOptions else []))
+    (lib.collect lib.isOption options);
+in
+  document nixos.options
+"#
+    )
+}
+
+fn by_name(options: Vec<OptionInfo>) -> BTreeMap<String, OptionInfo> {
+    options
+        .into_iter()
+        .map(|option| (option.name.clone(), option))
+        .collect()
+}
+
+async fn build_index(channel: &'static str, revision: String) -> Result<(), Error> {
+    // Evaluated in a checkout of its own, as building the index takes far too long to hold up
+    // updating the channel's.
+    let pinned = pinned_checkout(&revision).await?;
+    let checkout = pinned.path();
+    info!("Indexing the NixOS options in nixpkgs {revision}.");
+    let options = EvalOptions {
+        mode: Mode::Strict,
+        output: Output::Json,
+        timeout: Duration::from_secs(ARGS.index_timeout),
+        checkout: checkout.to_path_buf(),
+    };
+    let output = evaluate_in_background(index_expression(), options).await?;
+    if nixpkgs_revision(channel).as_deref() != Some(revision.as_str()) {
+        return Err(Error::from(format!(
+            "nixpkgs moved away from {revision} while it was being indexed."
+        )));
+    }
+    let mut options: Vec<OptionInfo> = serde_json::from_str(&output.value)?;
+    for option in &mut options {
+        for declaration in &mut option.declarations {
+            *declaration = relative_position(declaration, checkout).to_string();
+        }
+    }
+
+    info!(
+        "Finished indexing {} NixOS options in nixpkgs {revision}.",
+        options.len()
+    );
+    INDEXES.store(revision, by_name(options)).await
+}
+
+/// The configured channel called `channel`, or the default one.
+fn configured_channel(channel: Option<&str>) -> &'static str {
+    ARGS.channels
+        .iter()
+        .find(|configured| Some(configured.as_str()) == channel)
+        .map_or_else(default_channel, String::as_str)
+}
+
+/// The option index of `channel`, starting to build it if there isn't one yet.
+async fn option_index(
+    channel: Option<&str>,
+) -> Result<Option<Arc<BTreeMap<String, OptionInfo>>>, Error> {
+    channel_checkout(channel)?;
+    // Validated above, so it's one of the configured channels.
+    let channel = configured_channel(channel);
+    let revision = nixpkgs_revision(channel).ok_or_else(unavailable)?;
+    let index = INDEXES.get(&revision).await;
+    if index.is_none() {
+        if let Some(error) = BUILDS.failure(&revision) {
+            return Err(error);
+        }
+        BUILDS.start(revision.clone(), build_index(channel, revision));
+    }
+    Ok(index)
+}
+
+/// The names of the options in `index` starting with `prefix`, in order.
+fn names_starting_with<'a>(
+    index: &'a BTreeMap<String, OptionInfo>,
+    prefix: &'a str,
+) -> impl Iterator<Item = &'a String> {
+    // Names starting with `prefix` sort right after it, up to the first one that doesn't.
+    index
+        .range(prefix.to_string()..)
+        .map(|(name, _)| name)
+        .take_while(move |name| name.starts_with(prefix))
+}
+
+async fn autocomplete_option(
+    ctx: Context<'_, (), Error>,
+    partial: &str,
+) -> CreateAutocompleteResponse {
+    let Ok(Some(index)) = option_index(chosen_channel(ctx).as_deref()).await else {
+        return CreateAutocompleteResponse::new();
+    };
+
+    let choices = names_starting_with(&index, partial.trim())
+        .take(AUTOCOMPLETE_CHOICES)
+        .map(AutocompleteChoice::from)
+        .collect();
+    CreateAutocompleteResponse::new().set_choices(choices)
+}
+
+#[command(
+    slash_command,
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub(crate) async fn option(
+    ctx: Context<'_, (), Error>,
+    #[autocomplete = "autocomplete_option"]
+    #[description = "NixOS option path"]
+    path: String,
+    #[autocomplete = "autocomplete_channel"]
+    #[description = "Nixpkgs channel to look in"]
+    channel: Option<String>,
+) -> Result<(), Error> {
+    let Some(index) = option_index(channel.as_deref()).await? else {
+        ctx.say(
+            "The NixOS options are still being indexed, which takes a few minutes. Try again later.",
+        )
+        .await?;
+        return Ok(());
+    };
+    let option = index
+        .get(path.trim())
+        .ok_or_else(|| format!("`{path}` isn't a NixOS option."))?;
+
+    let description = option.description.as_deref().map_or_else(
+        || String::from("No description."),
+        |description| MYST_ROLE.replace_all(description, "`").into_owned(),
+    );
+    let mut embed: CreateEmbed = CreateEmbed::new()
+        .title(&option.name)
+        .description(truncate(&description, EMBED_DESCRIPTION_LIMIT))
+        .color(Color::from((35, 127, 235)));
+    if let Some(kind) = &option.r#type {
+        let kind = if option.read_only {
+            format!("{kind} (read only)")
+        } else {
+            kind.clone()
+        };
+        embed = embed.field("Type", truncate(&kind, EMBED_FIELD_LIMIT), false);
+    }
+    if let Some(default) = &option.default {
+        embed = embed.field("Default", code_block(default, "nix"), false);
+    }
+    if let Some(example) = &option.example {
+        embed = embed.field("Example", code_block(example, "nix"), false);
+    }
+    if !option.declarations.is_empty() {
+        let declarations = format!("Declared in {}", option.declarations.join(", "));
+        embed = embed.footer(CreateEmbedFooter::new(truncate(
+            &declarations,
+            EMBED_FOOTER_LIMIT,
+        )));
+    }
+
+    ctx.send(CreateReply::default().embed(embed).reply(true))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(name: &str) -> OptionInfo {
+        OptionInfo {
+            name: name.to_string(),
+            r#type: None,
+            default: None,
+            example: None,
+            description: None,
+            declarations: Vec::new(),
+            read_only: false,
+        }
+    }
+
+    #[test]
+    fn myst_roles_become_plain_code() {
+        let description = "Enables {option}`services.foo.enable` by running {command}`foo`.";
+        assert_eq!(
+            MYST_ROLE.replace_all(description, "`"),
+            "Enables `services.foo.enable` by running `foo`."
+        );
+        assert_eq!(MYST_ROLE.replace_all("{not a role}", "`"), "{not a role}");
+    }
+
+    #[test]
+    fn names_are_found_by_prefix() {
+        let index = by_name(
+            ["a", "a.b", "a.c", "ab", "b"]
+                .into_iter()
+                .map(option)
+                .collect(),
+        );
+        let found = |prefix| {
+            names_starting_with(&index, prefix)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(found("a."), ["a.b", "a.c"]);
+        assert_eq!(found("a"), ["a", "a.b", "a.c", "ab"]);
+        assert_eq!(found(""), ["a", "a.b", "a.c", "ab", "b"]);
+        assert!(found("c").is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::Error;
+use crate::commands::snix;
+use crate::commands::snix::evaluator::{
+    EvalOptions, Mode, Output, attribute_names, evaluate_expression,
+};
+use crate::commands::snix::{AUTOCOMPLETE_CHOICES, autocomplete_channel};
+use poise::serenity_prelude::{AutocompleteChoice, Color, CreateAutocompleteResponse, CreateEmbed};
+use poise::{Context, CreateReply, command};
+use serde::Deserialize;
+use std::path::Path;
+
+/// How many platforms to list before just counting the rest.
+const SHOWN_PLATFORMS: usize = 10;
+
+async fn autocomplete_package(
+    _ctx: Context<'_, (), Error>,
+    partial: &str,
+) -> CreateAutocompleteResponse {
+    let (parent, prefix) = partial.rsplit_once('.').unwrap_or(("", partial));
+    let parent_expression = format!("pkgs{}", snix::attribute_selector(parent));
+    let names = attribute_names(&parent_expression).await;
+
+    let choices = names
+        .iter()
+        .filter(|name| name.starts_with(prefix))
+        .take(AUTOCOMPLETE_CHOICES)
+        .map(|name| {
+            if parent.is_empty() {
+                AutocompleteChoice::from(name)
+            } else {
+                AutocompleteChoice::from(format!("{parent}.{name}"))
+            }
+        })
+        .collect();
+    CreateAutocompleteResponse::new().set_choices(choices)
+}
+
+#[derive(Deserialize)]
+struct PackageInfo {
+    name: Option<String>,
+    version: Option<String>,
+    description: Option<String>,
+    licenses: Vec<String>,
+    homepages: Vec<String>,
+    platforms: Vec<String>,
+    maintainers: Vec<String>,
+    position: Option<String>,
+}
+
+fn package_expression(attribute: &str) -> String {
+    format!(
+        r#"
+let
+  package = pkgs{selector};
+  meta = package.meta or {{}};
+  toList = value: if builtins.isList value then value else [value];
+  licenseName = license:
+    if builtins.isAttrs license
+    then license.spdxId or license.shortName or license.fullName or "unknown"
+    else toString license;
+in
+  if !(builtins.isAttrs package)
+  then throw ({name} + " is a " + builtins.typeOf package + ", not a package")
+  else {{
+    name = package.pname or package.name or null;
+    version = package.version or null;
+    description = meta.description or null;
+    licenses = map licenseName (toList (meta.license or []));
+    homepages = toList (meta.homepage or []);
+    platforms = builtins.filter builtins.isString (meta.platforms or []);
+    maintainers = map (maintainer: maintainer.github or maintainer.name or "unknown") (meta.maintainers or []);
+    position = meta.position or null;
+  }}
+"#,
+        selector = snix::attribute_selector(attribute),
+        name = snix::nix_string_literal(&format!("pkgs.{attribute}")),
+    )
+}
+
+#[command(
+    slash_command,
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub(crate) async fn package(
+    ctx: Context<'_, (), Error>,
+    #[autocomplete = "autocomplete_package"]
+    #[description = "Attribute path under pkgs"]
+    attribute: String,
+    #[autocomplete = "autocomplete_channel"]
+    #[description = "Nixpkgs channel to look in"]
+    channel: Option<String>,
+) -> Result<(), Error> {
+    let options = EvalOptions::new(
+        ctx.guild_id(),
+        channel.as_deref(),
+        Some(Mode::Strict),
+        Some(Output::Json),
+        None,
+    )?;
+    let checkout = options.checkout.clone();
+    let output = evaluate_expression(package_expression(&attribute), Vec::new(), options).await?;
+    let package: PackageInfo = serde_json::from_str(&output.value)?;
+
+    let mut embed: CreateEmbed = CreateEmbed::new()
+        .title(format!("pkgs.{attribute}"))
+        .color(Color::from((35, 127, 235)));
+    if let Some(description) = &package.description {
+        embed = embed.description(description);
+    }
+    if let Some(homepage) = package.homepages.first() {
+        embed = embed.url(homepage);
+    }
+    embed = snix::add_embed_text_field(embed, "Name", package.name.as_deref());
+    embed = snix::add_embed_text_field(embed, "Version", package.version.as_deref());
+    embed = snix::add_embed_text_field(embed, "License", list(&package.licenses).as_deref());
+    embed = snix::add_embed_text_field(embed, "Homepage", list(&package.homepages).as_deref());
+    embed =
+        snix::add_embed_text_field(embed, "Platforms", platforms(&package.platforms).as_deref());
+    embed = snix::add_embed_text_field(embed, "Maintainers", list(&package.maintainers).as_deref());
+    embed = snix::add_embed_text_field(
+        embed,
+        "Defined In",
+        package
+            .position
+            .as_deref()
+            .map(|position| relative_position(position, &checkout)),
+    );
+
+    ctx.send(CreateReply::default().embed(embed).reply(true))
+        .await?;
+    Ok(())
+}
+
+fn list(items: &[String]) -> Option<String> {
+    (!items.is_empty()).then(|| items.join(", "))
+}
+
+fn platforms(platforms: &[String]) -> Option<String> {
+    let mut shown = list(&platforms[..platforms.len().min(SHOWN_PLATFORMS)])?;
+    if platforms.len() > SHOWN_PLATFORMS {
+        shown = format!("{shown} and {} more", platforms.len() - SHOWN_PLATFORMS);
+    }
+    Some(shown)
+}
+
+/// `meta.position` is an absolute path into the checkout, which is only interesting relative to
+/// the nixpkgs root.
+pub(crate) fn relative_position<'a>(position: &'a str, checkout: &Path) -> &'a str {
+    // Imported files are canonicalized, so the checkout has to be as well.
+    let checkout = checkout
+        .canonicalize()
+        .unwrap_or_else(|_| checkout.to_path_buf());
+    position
+        .strip_prefix(&*checkout.to_string_lossy())
+        .map_or(position, |relative| relative.trim_start_matches('/'))
+}
@@ -1,15 +1,72 @@
 use crate::Error;
-use crate::commands::snix::check_value_for_errors;
-use crate::commands::snix::io::NixpkgsIo;
-use crate::nixpkgs::NIXPKGS_PATH;
+use crate::args::ARGS;
+use crate::commands::snix::evaluator::{
+    EvalOptions, EvalOutput, Mode, Output, attribute_names, evaluate_expression,
+};
+use crate::commands::snix::session::{self, SessionKey};
+use crate::commands::snix::{
+    self, AUTOCOMPLETE_CHOICES, AUTOCOMPLETE_NAME_LIMIT, autocomplete_channel,
+};
+use crate::error_embed;
+use crate::nixpkgs::revision_checkout;
+use log::trace;
 use poise::futures_util::future::join_all;
-use poise::serenity_prelude::Message;
-use poise::{Context, command};
+use poise::serenity_prelude::{
+    AutocompleteChoice, CacheHttp, ChannelId, CreateAttachment, CreateAutocompleteResponse,
+    EditMessage, MESSAGE_CODE_LIMIT, Message, MessageId,
+};
+use poise::{Context, CreateReply, command};
 use regex::{Captures, Regex};
 use rustc_hash::FxHashMap;
-use snix_eval::{EvalMode, GlobalsMap, Value};
-use std::rc::Rc;
-use tokio::time::{Duration, timeout};
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::{Duration, Instant};
+
+/// Suggests what could come next when the expression ends in an attribute path rooted at
+/// `lib`, `pkgs` or `builtins`, like `lib.strings.con`.
+async fn autocomplete_expression(
+    _ctx: Context<'_, (), Error>,
+    partial: &str,
+) -> CreateAutocompleteResponse {
+    let is_identifier = |character: char| {
+        character.is_ascii_alphanumeric() || matches!(character, '_' | '\'' | '-')
+    };
+    let path_start = partial
+        .rfind(|character: char| !is_identifier(character) && character != '.')
+        .map_or(0, |index| index + 1);
+    let (before, path) = partial.split_at(path_start);
+    let Some((parent, prefix)) = path.rsplit_once('.') else {
+        return CreateAutocompleteResponse::new();
+    };
+    let (root, attributes) = parent.split_once('.').unwrap_or((parent, ""));
+    if !matches!(root, "lib" | "pkgs" | "builtins") {
+        return CreateAutocompleteResponse::new();
+    }
+
+    let parent_expression = format!("{root}{}", snix::attribute_selector(attributes));
+    let names = attribute_names(&parent_expression).await;
+
+    let choices = names
+        .iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| {
+            // Anything that isn't a plain identifier has to be quoted to be selected.
+            let plain = name
+                .starts_with(|character: char| character.is_ascii_alphabetic() || character == '_')
+                && name.chars().all(is_identifier);
+            if plain {
+                format!("{before}{parent}.{name}")
+            } else {
+                format!("{before}{parent}.{}", snix::nix_string_literal(name))
+            }
+        })
+        .filter(|choice| choice.chars().count() <= AUTOCOMPLETE_NAME_LIMIT)
+        .take(AUTOCOMPLETE_CHOICES)
+        .map(AutocompleteChoice::from)
+        .collect();
+    CreateAutocompleteResponse::new().set_choices(choices)
+}
 
 #[command(
     slash_command,
@@ -17,10 +74,57 @@ use tokio::time::{Duration, timeout};
     interaction_context = "Guild|BotDm|PrivateChannel"
 )]
 pub(crate) async fn eval(
+    ctx: Context<'_, (), Error>,
+    #[autocomplete = "autocomplete_expression"]
+    #[description = "Expression"]
+    expression: String,
+    #[description = "Force the whole result, or only its outermost value"] mode: Option<Mode>,
+    #[description = "How to show the result"] output: Option<Output>,
+    #[description = "Seconds to allow the evaluation, up to the configured maximum"]
+    #[min = 1]
+    timeout: Option<u64>,
+    #[autocomplete = "autocomplete_channel"]
+    #[description = "Nixpkgs channel to evaluate against"]
+    channel: Option<String>,
+    #[description = "Nixpkgs commit hash, branch or pr:NUMBER to evaluate against instead"]
+    rev: Option<String>,
+) -> Result<(), Error> {
+    let mut options = EvalOptions::new(ctx.guild_id(), channel.as_deref(), mode, output, timeout)?;
+    // Kept until the evaluation is done, so the checkout isn't removed from under it.
+    let mut revision = None;
+    if let Some(rev) = rev {
+        if channel.is_some() {
+            return Err(Error::from("Pick either a channel or a rev, not both."));
+        }
+        // Fetching a revision can take a while, so defer the interaction.
+        ctx.defer().await?;
+        let checkout = revision.insert(revision_checkout(rev.trim()).await?);
+        options.checkout = checkout.path().to_path_buf();
+    }
+    eval_discord_expression(ctx, expression, options).await
+}
+
+#[command(
+    slash_command,
+    rename = "type",
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub(crate) async fn type_of(
     ctx: Context<'_, (), Error>,
     #[description = "Expression"] expression: String,
+    #[autocomplete = "autocomplete_channel"]
+    #[description = "Nixpkgs channel to evaluate against"]
+    channel: Option<String>,
 ) -> Result<(), Error> {
-    eval_discord_expression(ctx, expression).await
+    let options = EvalOptions::new(
+        ctx.guild_id(),
+        channel.as_deref(),
+        Some(Mode::Lazy),
+        Some(Output::Type),
+        None,
+    )?;
+    eval_discord_expression(ctx, expression, options).await
 }
 
 #[command(
@@ -32,9 +136,38 @@ pub(crate) async fn eval_code_block(
     ctx: Context<'_, (), Error>,
     #[description = "Message"] message: Message,
 ) -> Result<(), Error> {
+    let expression = extract_code_block(&message.content)?;
+    let session = SessionKey::from_context(ctx);
+    let options = EvalOptions::new(ctx.guild_id(), None, None, None, None)?;
+
+    // Errors are shown publicly here rather than through the usual ephemeral reply, so that
+    // fixing the code block can replace them with the result.
+    let reply = render_evaluation(session, expression.to_string(), options.clone())
+        .await
+        .unwrap_or_else(|error| error_reply(&error));
+    let handle = ctx.send(reply).await?;
+    // Edits can only be seen with the message content intent.
+    if !ARGS.code_block_evaluation {
+        return Ok(());
+    }
+    let reply = handle.message().await?;
+    track_edits(
+        message.id,
+        TrackedEvaluation {
+            reply_channel: reply.channel_id,
+            reply: reply.id,
+            session,
+            options,
+            tracked_at: Instant::now(),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+fn extract_code_block(content: &str) -> Result<&str, Error> {
     // Find the start of the Nix code block (`nix\n`).
-    let expression = message
-        .content
+    let expression = content
         .split_once("```nix\n")
         .ok_or("Couldn't find Nix code block!")?
         .1;
@@ -44,14 +177,73 @@ pub(crate) async fn eval_code_block(
         .rsplit_once("```")
         .ok_or("Couldn't find the end of the Nix code block!")?
         .0;
+    Ok(expression)
+}
+
+/// How long after evaluating a code block edits to it are still picked up.
+const TRACK_EDITS_FOR: Duration = Duration::from_secs(60 * 60);
+
+/// Code blocks evaluated through the context menu, by the ID of the message they're in.
+static TRACKED_EVALUATIONS: LazyLock<Mutex<FxHashMap<MessageId, TrackedEvaluation>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+#[derive(Clone)]
+struct TrackedEvaluation {
+    reply_channel: ChannelId,
+    reply: MessageId,
+    session: SessionKey,
+    options: EvalOptions,
+    tracked_at: Instant,
+}
+
+async fn tracked_evaluations() -> MutexGuard<'static, FxHashMap<MessageId, TrackedEvaluation>> {
+    let mut tracked = TRACKED_EVALUATIONS.lock().await;
+    tracked.retain(|_, evaluation| evaluation.tracked_at.elapsed() < TRACK_EDITS_FOR);
+    tracked
+}
+
+async fn track_edits(message: MessageId, evaluation: TrackedEvaluation) {
+    tracked_evaluations().await.insert(message, evaluation);
+}
+
+/// Re-evaluates an edited message if it's one we evaluated before, editing our reply to match.
+pub(crate) async fn reevaluate(
+    cache_http: impl CacheHttp,
+    message: MessageId,
+    content: &str,
+) -> Result<(), Error> {
+    let Some(tracked) = tracked_evaluations().await.get(&message).cloned() else {
+        return Ok(());
+    };
+    trace!("Re-evaluating edited message {message}.");
+
+    let reply = match extract_code_block(content) {
+        Ok(expression) => {
+            render_evaluation(tracked.session, expression.to_string(), tracked.options).await
+        }
+        Err(error) => Err(error),
+    };
+    let reply = reply.unwrap_or_else(|error| error_reply(&error));
+    tracked
+        .reply_channel
+        .edit_message(
+            cache_http,
+            tracked.reply,
+            // The content is always replaced, so an earlier error or result doesn't linger.
+            reply.to_prefix_edit(EditMessage::new().content("")),
+        )
+        .await?;
+    Ok(())
+}
 
-    // Call the original `eval` function with the extracted Expression.
-    eval_discord_expression(ctx, expression.to_string()).await
+fn error_reply(error: &Error) -> CreateReply {
+    CreateReply::default().embed(error_embed(error))
 }
 
-struct Assignment {
-    name: String,
-    value: String,
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Assignment {
+    pub(crate) name: String,
+    pub(crate) value: String,
 }
 
 enum ToEvaluateType {
@@ -59,155 +251,272 @@ enum ToEvaluateType {
     Assignment(Vec<Assignment>),
 }
 
+/// Splits `to_evaluate` into the assignments on each line starting with `name = `, if there are
+/// any. Each value runs up to the next assignment, and may end in a `;` like in the Nix REPL.
+fn parse_evaluation(to_evaluate: &str) -> ToEvaluateType {
+    let regex = Regex::new(r"(?m)^(?P<assignment>(?P<variable_name>[^\s]+)\s*= )").unwrap();
+    let matches: Vec<Captures> = regex.captures_iter(to_evaluate).collect();
+    if matches.is_empty() {
+        return ToEvaluateType::Expression(to_evaluate.to_string());
+    }
+
+    let mut assignments = Vec::new();
+    for (index, capture) in matches.iter().enumerate() {
+        let start = capture.name("assignment").unwrap().end();
+        let end = matches
+            .get(index + 1)
+            .map_or(to_evaluate.len(), |next| next.get(0).unwrap().start());
+        let value = to_evaluate[start..end].trim();
+        let value = value.strip_suffix(';').unwrap_or(value).trim_end();
+        assignments.push(Assignment {
+            name: capture.name("variable_name").unwrap().as_str().to_string(),
+            value: value.to_string(),
+        });
+    }
+    ToEvaluateType::Assignment(assignments)
+}
+
 async fn eval_discord_expression(
     ctx: Context<'_, (), Error>,
     to_evaluate: String,
+    options: EvalOptions,
 ) -> Result<(), Error> {
-    let response: String = {
-        let regex = Regex::new(r"(?m)^(?P<assignment>(?P<variable_name>[^\s]+)\s*= )").unwrap();
-        let matches: Vec<Captures> = regex.captures_iter(&to_evaluate).collect();
-
-        let to_eval_wrapped: ToEvaluateType = match matches.as_slice() {
-            [] => ToEvaluateType::Expression(to_evaluate.to_string()),
-            assignment_captures => {
-                let mut assignments = Vec::new();
-                for (index, capture) in assignment_captures.iter().enumerate() {
-                    let assignment = capture.name("assignment").unwrap();
-                    let expression_start_pos = assignment.end();
-                    let end = if index + 1 < assignment_captures.len() {
-                        assignment_captures[index + 1]
-                            .name("assignment")
-                            .unwrap()
-                            .start()
-                            - 2
-                    } else {
-                        to_evaluate.len() - 2
-                    };
-                    let variable_name = capture.name("variable_name").unwrap().as_str().to_string();
-                    let expression = to_evaluate[expression_start_pos..end].trim().to_string();
-
-                    assignments.push(Assignment {
-                        name: variable_name,
-                        value: expression,
-                    });
-                }
-                ToEvaluateType::Assignment(assignments)
-            }
-        };
+    let reply = render_evaluation(SessionKey::from_context(ctx), to_evaluate, options).await?;
+    ctx.send(reply).await?;
+    Ok(())
+}
 
-        match to_eval_wrapped {
-            ToEvaluateType::Expression(expression) => {
-                let output = evaluate_expression(expression).await?;
-                let formatted = format(output);
-                make_code_block(&formatted)
+async fn render_evaluation(
+    session_key: SessionKey,
+    to_evaluate: String,
+    mut options: EvalOptions,
+) -> Result<CreateReply, Error> {
+    // `:t expression` asks for the type of the expression, like it does in the Nix REPL.
+    let to_evaluate = match to_evaluate.trim_start().strip_prefix(":t ") {
+        Some(expression) => {
+            options.mode = Mode::Lazy;
+            options.output = Output::Type;
+            expression.to_string()
+        }
+        None => to_evaluate,
+    };
+    let bindings = session::bindings(session_key).await;
+    let mut traces: Vec<String> = Vec::new();
+    let mut warnings: Vec<String> = Vec::new();
+    let formatted: String = match parse_evaluation(&to_evaluate) {
+        ToEvaluateType::Expression(expression) => {
+            let output = evaluate_expression(expression, bindings, options.clone()).await?;
+            traces = output.traces;
+            warnings = output.warnings;
+            match options.output {
+                Output::Nix => format(output.value),
+                Output::Json | Output::Raw | Output::Type => output.value,
             }
-            ToEvaluateType::Assignment(assignments) => {
-                let evaluated_list: Vec<(String, String)> =
-                    join_all(assignments.into_iter().map(|assignment| async move {
-                        let name = assignment.name;
-                        let result = evaluate_expression(assignment.value).await?;
+        }
+        ToEvaluateType::Assignment(_) if options.output != Output::Nix => {
+            return Err(Error::from("Assignments can only be shown as Nix."));
+        }
+        ToEvaluateType::Assignment(assignments) => {
+            let evaluated_list: Vec<(String, EvalOutput)> =
+                join_all(assignments.iter().map(|assignment| {
+                    let bindings = bindings.clone();
+                    let options = options.clone();
+                    async move {
+                        let name = assignment.name.clone();
+                        let result =
+                            evaluate_expression(assignment.value.clone(), bindings, options)
+                                .await?;
                         Ok::<_, Error>((name, result))
-                    }))
-                    .await
-                    .into_iter()
-                    .collect::<Result<Vec<_>, Error>>()?;
-                let evaluated_list: Vec<String> = evaluated_list
-                    .into_iter()
-                    .map(|entry| format!("  {} = {};", entry.0, entry.1))
-                    .collect();
-                let attr_set = format!("{{\n{}\n}}", evaluated_list.join("\n"));
-                make_code_block(&format(attr_set))
-            }
+                    }
+                }))
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, Error>>()?;
+            // Only remember the assignments once all of them evaluated successfully.
+            session::bind(session_key, assignments).await?;
+            let evaluated_list: Vec<String> = evaluated_list
+                .into_iter()
+                .map(|(name, output)| {
+                    traces.extend(output.traces);
+                    warnings.extend(output.warnings);
+                    format!("  {name} = {};", output.value)
+                })
+                .collect();
+            let attr_set = format!("{{\n{}\n}}", evaluated_list.join("\n"));
+            format(attr_set)
         }
     };
-    ctx.say(response).await?;
-    Ok(())
+    let diagnostics = diagnostics(&traces, &warnings);
+    Ok(result_reply(&formatted, &diagnostics, options.output))
+}
+
+/// Renders trace output and evaluation warnings as their own sections, in the order they
+/// happened. Empty when there were none.
+fn diagnostics(traces: &[String], warnings: &[String]) -> String {
+    [("Trace", traces), ("Warnings", warnings)]
+        .into_iter()
+        .filter(|(_, lines)| !lines.is_empty())
+        .map(|(title, lines)| format!("**{title}**\n```\n{}\n```", lines.join("\n")))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Discord refuses attachments larger than this in guilds without boosts.
+const MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+
+/// Puts a formatted result in a code block followed by any `diagnostics`, attaching either as a
+/// file when they're too long to fit in a message.
+pub(crate) fn result_reply(formatted: &str, diagnostics: &str, output: Output) -> CreateReply {
+    let code_block = make_code_block(formatted, output.language());
+    let message = [code_block.as_str(), diagnostics]
+        .into_iter()
+        .filter(|section| !section.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n");
+    if message.chars().count() <= MESSAGE_CODE_LIMIT {
+        return CreateReply::default().content(message);
+    }
+
+    let mut notice = format!(
+        "The result is {} characters long, which is too long for a message, so it's attached as `{}` instead.",
+        formatted.chars().count(),
+        output.file_name()
+    );
+    let mut attachment = formatted;
+    if attachment.len() > MAX_ATTACHMENT_SIZE {
+        let mut end = MAX_ATTACHMENT_SIZE;
+        while !attachment.is_char_boundary(end) {
+            end -= 1;
+        }
+        attachment = &attachment[..end];
+        notice = format!(
+            "{notice} It's too big to attach whole, so only the first {end} bytes are included."
+        );
+    }
+    let mut reply = CreateReply::default().attachment(CreateAttachment::bytes(
+        attachment.as_bytes(),
+        output.file_name(),
+    ));
+    if diagnostics.is_empty() {
+        reply = reply.content(notice);
+    } else if notice.chars().count() + diagnostics.chars().count() < MESSAGE_CODE_LIMIT {
+        reply = reply.content(format!("{notice}\n{diagnostics}"));
+    } else {
+        reply = reply
+            .content(format!(
+                "{notice} Trace output and warnings are attached as `diagnostics.md`."
+            ))
+            .attachment(CreateAttachment::bytes(
+                diagnostics.as_bytes(),
+                "diagnostics.md",
+            ));
+    }
+    reply
 }
 
-fn make_code_block(string: &str) -> String {
-    let code_block_response: String = format!("```nix\n{string}\n```");
+pub(crate) fn make_code_block(string: &str, language: &str) -> String {
+    let code_block_response: String = format!("```{language}\n{string}\n```");
     code_block_response
 }
 
-fn format(nix: String) -> String {
+pub(crate) fn format(nix: String) -> String {
     let fmt_config = alejandra::config::Config::default();
     alejandra::format::in_memory(String::new(), nix, fmt_config).1
 }
 
-#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-const SYSTEM: &str = "x86_64-linux";
-
-async fn evaluate_expression(expression: String) -> Result<String, Error> {
-    let eval_timeout: Duration = Duration::from_secs(2);
-    let output: Result<String, Error> = timeout(
-        eval_timeout,
-        tokio::task::spawn_blocking(move || {
-            let evaluator = |expression: &str,
-                             globals: Option<Rc<GlobalsMap>>,
-                             env: &FxHashMap<_, Value>,
-                             mode: EvalMode| {
-                let mut builder = snix_eval::Evaluation::builder_impure()
-                    .mode(mode)
-                    .env(Some(env));
-                match globals {
-                    None => {
-                        let global_builder = |expression| {
-                            check_value_for_errors(
-                                snix_eval::Evaluation::builder_pure()
-                                    .build()
-                                    .evaluate(expression, None),
-                            )
-                        };
-                        let derivation =
-                            global_builder("arg: arg // {out={type=null;outputName=null;};}")?;
-                        let placeholder = global_builder("arg: arg")?;
-                        builder = builder.enable_import();
-                        builder = builder.add_builtins(vec![
-                            ("derivation", derivation),
-                            ("placeholder", placeholder),
-                        ]);
-                        builder = builder.io_handle(Box::new(NixpkgsIo));
-                    }
-                    Some(globals) => {
-                        builder = builder.with_globals(globals);
-                        builder = builder.io_handle(Box::new(NixpkgsIo));
-                    }
-                }
-                let evaluator = builder.build();
-                let globals = Rc::clone(&evaluator.globals());
-                let result = check_value_for_errors(
-                    evaluator.evaluate(expression, Some(NIXPKGS_PATH.as_path().into())),
-                )?;
-                Ok::<(Value, Rc<GlobalsMap>), Error>((result, globals))
-            };
-            let mut fx_hash_map: FxHashMap<_, _> = FxHashMap::default();
-            let lib = evaluator("import ./lib", None, &fx_hash_map, EvalMode::Lazy)?;
-            fx_hash_map.insert("lib".into(), lib.0);
-            let globals = lib.1;
-            let pkgs = evaluator(
-                &format!("import ./pkgs/top-level/default.nix {{localSystem = \"{SYSTEM}\";}}"),
-                Some(Rc::clone(&globals)),
-                &fx_hash_map,
-                EvalMode::Lazy,
-            )?;
-            fx_hash_map.insert("pkgs".into(), pkgs.0);
-
-            let result = evaluator(
-                &expression,
-                Some(Rc::clone(&globals)),
-                &fx_hash_map,
-                EvalMode::Strict,
-            )?;
-
-            Ok(format!("{}", result.0))
-        }),
-    )
-    .await
-    .map_err(|_| {
-        format!(
-            "Evaluation took too long. Max eval time is {} seconds.",
-            eval_timeout.as_secs()
-        )
-    })??;
-    output
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assignments(to_evaluate: &str) -> Vec<(String, String)> {
+        match parse_evaluation(to_evaluate) {
+            ToEvaluateType::Assignment(assignments) => assignments
+                .into_iter()
+                .map(|assignment| (assignment.name, assignment.value))
+                .collect(),
+            ToEvaluateType::Expression(expression) => {
+                panic!("{expression:?} wasn't parsed as assignments")
+            }
+        }
+    }
+
+    #[test]
+    fn assignments_from_slash_commands_are_parsed() {
+        let parsed = |value: &str| (String::from("x"), value.to_string());
+        assert_eq!(assignments("x = 1"), [parsed("1")]);
+        assert_eq!(assignments("x = 123"), [parsed("123")]);
+        assert_eq!(assignments("x = 1;"), [parsed("1")]);
+        assert_eq!(assignments("x = { a = 1; };"), [parsed("{ a = 1; }")]);
+    }
+
+    #[test]
+    fn assignments_from_code_blocks_are_parsed() {
+        let parsed = assignments("x = 1;\ny = [\n  2\n];\nz = x + 1\n");
+        let expected = [("x", "1"), ("y", "[\n  2\n]"), ("z", "x + 1")]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn expressions_are_not_assignments() {
+        assert!(matches!(
+            parse_evaluation("a == b"),
+            ToEvaluateType::Expression(expression) if expression == "a == b"
+        ));
+    }
+
+    /// How much longer a result gets in a JSON code block.
+    const FENCES: usize = "```json\n\n```".len();
+
+    #[test]
+    fn short_results_are_sent_as_is() {
+        let reply = result_reply("1", "", Output::Json);
+        assert_eq!(reply.content.as_deref(), Some("```json\n1\n```"));
+        assert!(reply.attachments.is_empty());
+
+        let reply = result_reply("1", "**Trace**", Output::Json);
+        assert_eq!(reply.content.as_deref(), Some("```json\n1\n```\n**Trace**"));
+        assert!(reply.attachments.is_empty());
+    }
+
+    #[test]
+    fn results_over_the_limit_are_attached() {
+        let fits = "x".repeat(MESSAGE_CODE_LIMIT - FENCES);
+        let reply = result_reply(&fits, "", Output::Json);
+        assert!(reply.attachments.is_empty());
+
+        let too_long = format!("{fits}x");
+        let reply = result_reply(&too_long, "", Output::Json);
+        assert_eq!(reply.attachments.len(), 1);
+        assert_eq!(reply.attachments[0].filename, "result.json");
+        assert_eq!(reply.attachments[0].data, too_long.as_bytes());
+        let content = reply.content.expect("The reply explains the attachment.");
+        assert!(content.contains(&format!("{} characters", too_long.len())));
+    }
+
+    #[test]
+    fn long_diagnostics_are_attached_as_well() {
+        let too_long = "x".repeat(MESSAGE_CODE_LIMIT);
+        let diagnostics = "y".repeat(MESSAGE_CODE_LIMIT);
+        let reply = result_reply(&too_long, &diagnostics, Output::Nix);
+        let names: Vec<&str> = reply
+            .attachments
+            .iter()
+            .map(|attachment| attachment.filename.as_str())
+            .collect();
+        assert_eq!(names, ["result.nix", "diagnostics.md"]);
+        assert_eq!(reply.attachments[1].data, diagnostics.as_bytes());
+    }
+
+    #[test]
+    fn huge_results_are_cut_between_characters() {
+        // Every character after the first starts at an odd byte, so the limit falls inside one.
+        let huge = format!("a{}", "é".repeat(MAX_ATTACHMENT_SIZE / 2));
+        let reply = result_reply(&huge, "", Output::Raw);
+        let attached = &reply.attachments[0].data;
+        assert_eq!(attached.len(), MAX_ATTACHMENT_SIZE - 1);
+        assert!(std::str::from_utf8(attached).is_ok());
+        let content = reply.content.expect("The reply explains the attachment.");
+        assert!(content.contains(&format!("first {} bytes", MAX_ATTACHMENT_SIZE - 1)));
+    }
 }
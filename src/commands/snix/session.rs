@@ -0,0 +1,163 @@
+use crate::Error;
+use crate::args::ARGS;
+use crate::commands::snix::evaluator::Output;
+use crate::commands::snix::repl::{Assignment, format, result_reply};
+use poise::serenity_prelude::{ChannelId, UserId};
+use poise::{Context, command};
+use rustc_hash::FxHashMap;
+use std::sync::LazyLock;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::{Duration, Instant};
+
+/// Every binding gets replayed before each evaluation in the session, rebindings included, so
+/// sessions are kept to this many.
+const MAX_BINDINGS: usize = 64;
+
+static SESSIONS: LazyLock<Mutex<FxHashMap<SessionKey, Session>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct SessionKey {
+    user: UserId,
+    channel: Option<ChannelId>,
+}
+
+impl SessionKey {
+    pub(crate) fn new(user: UserId, channel: ChannelId) -> Self {
+        let channel = ARGS.per_channel_sessions.then_some(channel);
+        Self { user, channel }
+    }
+
+    pub(crate) fn from_context(ctx: Context<'_, (), Error>) -> Self {
+        Self::new(ctx.author().id, ctx.channel_id())
+    }
+}
+
+struct Session {
+    // Every assignment in the order it was made. Later entries shadow earlier ones,
+    // so anything bound before a rebinding keeps seeing the value it was defined with.
+    bindings: Vec<Assignment>,
+    last_used: Instant,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            bindings: Vec::new(),
+            last_used: Instant::now(),
+        }
+    }
+
+    /// The most recent binding for each name, in the order they were bound.
+    fn visible_bindings(&self) -> Vec<&Assignment> {
+        self.bindings
+            .iter()
+            .enumerate()
+            .filter(|(index, binding)| {
+                !self.bindings[index + 1..]
+                    .iter()
+                    .any(|later| later.name == binding.name)
+            })
+            .map(|(_, binding)| binding)
+            .collect()
+    }
+}
+
+/// Locks the session store, dropping any session that has sat idle for too long first.
+async fn live_sessions() -> MutexGuard<'static, FxHashMap<SessionKey, Session>> {
+    let idle_timeout = Duration::from_secs(ARGS.session_idle_timeout);
+    let mut sessions = SESSIONS.lock().await;
+    sessions.retain(|_, session| session.last_used.elapsed() < idle_timeout);
+    sessions
+}
+
+pub(crate) async fn bindings(key: SessionKey) -> Vec<Assignment> {
+    let mut sessions = live_sessions().await;
+    sessions
+        .get_mut(&key)
+        .map(|session| {
+            session.last_used = Instant::now();
+            session.bindings.clone()
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) async fn bind(key: SessionKey, assignments: Vec<Assignment>) -> Result<(), Error> {
+    let mut sessions = live_sessions().await;
+    let session = sessions.entry(key).or_insert_with(Session::new);
+    if session.bindings.len() + assignments.len() > MAX_BINDINGS {
+        return Err(Error::from(format!(
+            "Sessions can hold at most {MAX_BINDINGS} bindings, rebindings included. Use `/session drop` or `/session reset` to make room."
+        )));
+    }
+    session.bindings.extend(assignments);
+    session.last_used = Instant::now();
+    Ok(())
+}
+
+#[command(
+    slash_command,
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel",
+    subcommands("list_bindings", "drop_binding", "reset_session"),
+    subcommand_required
+)]
+pub(crate) async fn session(_ctx: Context<'_, (), Error>) -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(slash_command, rename = "list")]
+async fn list_bindings(ctx: Context<'_, (), Error>) -> Result<(), Error> {
+    let sessions = live_sessions().await;
+    let bindings: Vec<String> = sessions
+        .get(&SessionKey::from_context(ctx))
+        .map(|session| {
+            session
+                .visible_bindings()
+                .into_iter()
+                .map(|binding| format!("  {} = {};", binding.name, binding.value))
+                .collect()
+        })
+        .unwrap_or_default();
+    drop(sessions);
+
+    if bindings.is_empty() {
+        ctx.say("You have no session bindings.").await?;
+    } else {
+        let attr_set = format!("{{\n{}\n}}", bindings.join("\n"));
+        ctx.send(result_reply(&format(attr_set), "", Output::Nix))
+            .await?;
+    }
+    Ok(())
+}
+
+#[command(slash_command, rename = "drop")]
+async fn drop_binding(
+    ctx: Context<'_, (), Error>,
+    #[description = "Name of the binding to forget"] name: String,
+) -> Result<(), Error> {
+    let mut sessions = live_sessions().await;
+    let session = sessions
+        .get_mut(&SessionKey::from_context(ctx))
+        .ok_or("You have no session bindings.")?;
+    let bound_before = session.bindings.len();
+    session.bindings.retain(|binding| binding.name != name);
+    if session.bindings.len() == bound_before {
+        return Err(Error::from(format!(
+            "`{name}` is not bound in your session."
+        )));
+    }
+    session.last_used = Instant::now();
+    drop(sessions);
+
+    ctx.say(format!("Dropped `{name}` from your session."))
+        .await?;
+    Ok(())
+}
+
+#[command(slash_command, rename = "reset")]
+async fn reset_session(ctx: Context<'_, (), Error>) -> Result<(), Error> {
+    live_sessions().await.remove(&SessionKey::from_context(ctx));
+    ctx.say("Your session has been reset.").await?;
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use crate::Error;
+use crate::commands::snix;
+use crate::commands::snix::evaluator::{
+    EvalOptions, Mode, Output, attribute_names, evaluate_expression,
+};
+use crate::commands::snix::maintainer::NAME_FUNCTIONS;
+use crate::commands::snix::{AUTOCOMPLETE_CHOICES, EMBED_DESCRIPTION_LIMIT, autocomplete_channel};
+use poise::serenity_prelude::{
+    AutocompleteChoice, Color, CreateAutocompleteResponse, CreateEmbed, CreateEmbedFooter,
+};
+use poise::{Context, CreateReply, command};
+use serde::Deserialize;
+
+async fn autocomplete_team(
+    _ctx: Context<'_, (), Error>,
+    partial: &str,
+) -> CreateAutocompleteResponse {
+    let teams = attribute_names("lib.teams").await;
+
+    let partial = partial.to_lowercase();
+    let choices = teams
+        .iter()
+        .filter(|team| team.to_lowercase().starts_with(&partial))
+        .take(AUTOCOMPLETE_CHOICES)
+        .map(AutocompleteChoice::from)
+        .collect();
+    CreateAutocompleteResponse::new().set_choices(choices)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TeamInfo {
+    short_name: Option<String>,
+    scope: Option<String>,
+    members: Vec<Member>,
+    github_teams: Vec<String>,
+    matrix_room: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Member {
+    /// The member's name in `lib.maintainers`, which is what `/maintainer` looks them up by.
+    key: Option<String>,
+    name: Option<String>,
+    github: Option<String>,
+}
+
+impl Member {
+    fn describe(&self) -> String {
+        let name = self
+            .name
+            .as_deref()
+            .or(self.github.as_deref())
+            .or(self.key.as_deref())
+            .unwrap_or("Unknown");
+        let mut description = match &self.github {
+            Some(github) => format!("{name} ([@{github}](https://github.com/{github}))"),
+            None => name.to_string(),
+        };
+        if let Some(key) = &self.key {
+            description = format!("{description} `{key}`");
+        }
+        description
+    }
+}
+
+fn team_expression(team: &str) -> String {
+    format!(
+        r#"
+let
+  team = lib.teams{selector};
+  {NAME_FUNCTIONS}
+in {{
+  shortName = team.shortName or null;
+  scope = team.scope or null;
+  members = map (member: {{
+    key = nameOf member;
+    name = member.name or null;
+    github = member.github or null;
+  }}) (team.members or []);
+  githubTeams = team.githubTeams or (if team ? github then [team.github] else []);
+  matrixRoom = team.matrixRoom or team.matrix or null;
+}}
+"#,
+        selector = snix::attribute_selector(team),
+    )
+}
+
+#[command(
+    slash_command,
+    install_context = "Guild|User",
+    interaction_context = "Guild|BotDm|PrivateChannel"
+)]
+pub(crate) async fn team(
+    ctx: Context<'_, (), Error>,
+    #[autocomplete = "autocomplete_team"]
+    #[description = "Team Name"]
+    name: String,
+    #[autocomplete = "autocomplete_channel"]
+    #[description = "Nixpkgs channel to look in"]
+    channel: Option<String>,
+) -> Result<(), Error> {
+    let options = EvalOptions::new(
+        ctx.guild_id(),
+        channel.as_deref(),
+        Some(Mode::Strict),
+        Some(Output::Json),
+        None,
+    )?;
+    let output = evaluate_expression(team_expression(&name), Vec::new(), options).await?;
+    let team: TeamInfo = serde_json::from_str(&output.value)?;
+
+    let mut embed: CreateEmbed = CreateEmbed::new()
+        .title("Team Info")
+        .color(Color::from((35, 127, 235)));
+    if !team.members.is_empty() {
+        let mut members = format!("**Members** ({})", team.members.len());
+        for member in &team.members {
+            let line = format!("\n- {}", member.describe());
+            if members.chars().count() + line.chars().count() > EMBED_DESCRIPTION_LIMIT {
+                break;
+            }
+            members.push_str(&line);
+        }
+        embed = embed.description(members).footer(CreateEmbedFooter::new(
+            "Look a member up with /maintainer and the name after their handle.",
+        ));
+    }
+    embed = snix::add_embed_text_field(embed, "Name", Some(&name));
+    embed = snix::add_embed_text_field(embed, "Short Name", team.short_name.as_deref());
+    embed = snix::add_embed_text_field(embed, "Scope", team.scope.as_deref());
+    embed = snix::add_embed_text_field(
+        embed,
+        "GitHub Team",
+        (!team.github_teams.is_empty())
+            .then(|| {
+                team.github_teams
+                    .iter()
+                    .map(|github_team| format!("@NixOS/{github_team}"))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            })
+            .as_deref(),
+    );
+    embed = snix::add_embed_text_field(embed, "Matrix", team.matrix_room.as_deref());
+
+    ctx.send(CreateReply::default().embed(embed).reply(true))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,15 @@
+use crate::Context;
+use crate::commands::snix::repl;
+use log::{trace, warn};
+use poise::serenity_prelude::MessageUpdateEvent;
+
+pub(crate) async fn message_update(framework: Context<'_>, event: &MessageUpdateEvent) {
+    trace!("Received message update event.");
+    // Edits that don't touch the content, like embeds resolving, aren't interesting.
+    let Some(content) = &event.content else {
+        return;
+    };
+    if let Err(error) = repl::reevaluate(framework.serenity_context, event.id, content).await {
+        warn!("Couldn't re-evaluate edited message {}: {error}", event.id);
+    }
+}
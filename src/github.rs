@@ -0,0 +1,60 @@
+use crate::args::ARGS;
+use log::warn;
+use openapi_github::apis::configuration::Configuration;
+use openapi_github::apis::users_api::users_slash_get_by_username;
+use openapi_github::models::UsersGetAuthenticated200Response;
+use rustc_hash::FxHashMap;
+use std::sync::LazyLock;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// How long to wait on GitHub before giving up. Anything waiting on an avatar has a Discord
+/// interaction to answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to remember that a user's avatar couldn't be fetched, so that GitHub being down or
+/// rate limiting us doesn't make every lookup wait on it again.
+const FAILURE_TTL: Duration = Duration::from_secs(5 * 60);
+
+static GITHUB: LazyLock<Configuration> = LazyLock::new(|| Configuration {
+    base_path: ARGS.github_api_url.trim_end_matches('/').to_string(),
+    bearer_access_token: ARGS.github_token.clone(),
+    client: reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .unwrap_or_default(),
+    ..Configuration::default()
+});
+
+/// Avatar URLs by GitHub username, or `None` if fetching one failed, along with when they were
+/// fetched.
+static AVATARS: LazyLock<Mutex<FxHashMap<String, (Option<String>, Instant)>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+/// The avatar of a GitHub user, or `None` if GitHub can't tell us right now. Nothing should
+/// fail just because GitHub is unreachable or rate limiting us.
+pub(crate) async fn avatar_url(username: &str) -> Option<String> {
+    let ttl = Duration::from_secs(ARGS.github_cache_ttl);
+    let mut avatars = AVATARS.lock().await;
+    avatars.retain(|_, (avatar, fetched_at)| {
+        fetched_at.elapsed() < if avatar.is_some() { ttl } else { FAILURE_TTL }
+    });
+    if let Some((avatar, _)) = avatars.get(username) {
+        return avatar.clone();
+    }
+    drop(avatars);
+
+    let avatar: Option<String> = users_slash_get_by_username(&GITHUB, username)
+        .await
+        .inspect_err(|error| warn!("Couldn't fetch GitHub user {username}: {error}"))
+        .ok()
+        .map(|user| match user {
+            UsersGetAuthenticated200Response::PrivateUser(user) => user.avatar_url,
+            UsersGetAuthenticated200Response::PublicUser(user) => user.avatar_url,
+        });
+    AVATARS
+        .lock()
+        .await
+        .insert(username.to_string(), (avatar.clone(), Instant::now()));
+    avatar
+}
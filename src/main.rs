@@ -2,17 +2,23 @@
 mod args;
 mod commands;
 mod events;
+mod github;
 
 use args::ARGS;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 mod nixpkgs;
 
-use crate::nixpkgs::{NIXPKGS_REPO, nixpkgs_repo};
-use poise::serenity_prelude::{Client, Color, CreateEmbed};
+use env_logger::Target;
+use poise::serenity_prelude::{ChannelId, Client, Color, CreateEmbed, Http};
 use poise::{BoxFuture, CreateReply, FrameworkError, FrameworkOptions};
 use poise::{Command, Framework, serenity_prelude as serenity};
 use serenity::prelude::*;
 use std::error;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsFd;
+use std::sync::Arc;
+use tokio::time::{Duration, MissedTickBehavior};
 
 type Context<'a> = poise::FrameworkContext<'a, (), Error>;
 type Error = Box<dyn error::Error + Send + Sync>;
@@ -25,15 +31,28 @@ async fn main() {
     // During CLI args evaluation is a good example.
     init_logging();
 
+    if ARGS.eval_worker {
+        commands::snix::evaluator::run_worker();
+        return;
+    }
+
     // Let's go ahead and spawn a thread to clone nixpkgs, it will take a minute.
     tokio::spawn(async move {
-        let mut nixpkgs = NIXPKGS_REPO.lock().await;
-        let repository = nixpkgs_repo();
-        info!("Nixpkgs is ready at: {}", repository.path().display());
-        *nixpkgs = Some(repository);
+        nixpkgs::set_up_nixpkgs().await;
+        commands::snix::maintainer::rebuild_maintainer_index().await;
     });
 
-    let mut client: Client = build_client(&ARGS.token).await;
+    let token = ARGS
+        .token
+        .as_ref()
+        .expect("A token is required to run the bot!");
+    let mut client: Client = build_client(token).await;
+    if let Some(interval) = ARGS.update_interval.filter(|seconds| *seconds > 0) {
+        tokio::spawn(update_periodically(
+            Arc::clone(&client.http),
+            Duration::from_secs(interval),
+        ));
+    }
     info!("Starting client.");
     let result: serenity::Result<()> = client.start().await;
     info!("Client has shut down, finishing up.");
@@ -50,7 +69,15 @@ async fn main() {
 async fn build_client(token: &String) -> Client {
     let framework: Framework<(), Error> = build_framework();
 
-    let intents: GatewayIntents = GatewayIntents::empty();
+    // Message content is needed to re-evaluate code blocks when they're edited. It's a
+    // privileged intent, so it's only asked for when that's turned on.
+    let intents: GatewayIntents = if ARGS.code_block_evaluation {
+        GatewayIntents::GUILD_MESSAGES
+            | GatewayIntents::DIRECT_MESSAGES
+            | GatewayIntents::MESSAGE_CONTENT
+    } else {
+        GatewayIntents::empty()
+    };
     trace!("Building client.");
     let client: Client = Client::builder(token, intents)
         .framework(framework)
@@ -59,14 +86,51 @@ async fn build_client(token: &String) -> Client {
     client
 }
 
+/// Keeps nixpkgs up to date without anyone having to run `/nixpkgs_pull`, reporting each update
+/// in the admin log channel if there is one.
+async fn update_periodically(http: Arc<Http>, interval: Duration) {
+    let mut updates = tokio::time::interval(interval);
+    updates.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately, when nixpkgs has only just been set up.
+    updates.tick().await;
+    loop {
+        updates.tick().await;
+        info!("Starting scheduled nixpkgs update.");
+        let status = match commands::update_nixpkgs().await {
+            Ok(summary) => {
+                info!("{summary}");
+                summary
+            }
+            Err(error) => {
+                error!("Scheduled nixpkgs update failed: {error}");
+                format!("Scheduled nixpkgs update failed: {error}")
+            }
+        };
+        let Some(channel) = ARGS.admin_log_channel else {
+            continue;
+        };
+        if let Err(error) = ChannelId::new(channel).say(&http, status).await {
+            warn!("Couldn't report the nixpkgs update in the admin log channel: {error}");
+        }
+    }
+}
+
 fn build_framework() -> Framework<(), Error> {
     trace!("Collecting commands.");
     let commands: Vec<Command<(), Error>> = vec![
         commands::ping(),
         commands::snix::repl::eval(),
+        commands::snix::repl::type_of(),
+        commands::snix::repl::eval_code_block(),
         commands::snix::maintainer::maintainer(),
+        commands::snix::maintained_by::maintained_by(),
+        commands::snix::team::team(),
+        commands::snix::package::package(),
         commands::nixpkgs_pull(),
-        commands::snix::repl::eval_code_block(),
+        commands::status(),
+        commands::snix::session::session(),
+        commands::snix::doc::doc(),
+        commands::snix::option::option(),
         commands::noogle(),
     ];
     trace!("Building bot framework.");
@@ -92,27 +156,43 @@ fn error(error: FrameworkError<(), Error>) -> BoxFuture<()> {
     Box::pin(async move {
         match error {
             FrameworkError::Command { error, ctx, .. } => {
-                let embed: CreateEmbed = CreateEmbed::new()
-                    .title(String::from("Error"))
-                    .description(error.to_string())
-                    .color(Color::from((255, 0, 0)));
                 let reply: CreateReply = CreateReply::default()
-                    .embed(embed)
+                    .embed(error_embed(&error))
                     .ephemeral(true)
                     .reply(true);
-                ctx.send(reply).await.unwrap();
+                if let Err(send_error) = ctx.send(reply).await {
+                    error!("Couldn't reply with an error: {send_error}");
+                }
             }
             error => poise::builtins::on_error(error).await.unwrap(),
         }
     })
 }
 
+pub(crate) fn error_embed(error: &Error) -> CreateEmbed {
+    CreateEmbed::new()
+        .title(String::from("Error"))
+        .description(commands::snix::truncate(
+            &error.to_string(),
+            commands::snix::EMBED_DESCRIPTION_LIMIT,
+        ))
+        .color(Color::from((255, 0, 0)))
+}
+
 pub(crate) fn init_logging() {
     // Before now, logging is unavailable, therefore we may not log yet.
-    colog::default_builder()
+    let mut builder = colog::default_builder();
+    builder
         .filter(None, ARGS.dependency_log_level)
-        .filter(Some(env!("CARGO_CRATE_NAME")), ARGS.log_level)
-        .init();
+        .filter(Some(env!("CARGO_CRATE_NAME")), ARGS.log_level);
+    // Workers capture what evaluations print by pointing stderr at a file, which would catch
+    // their own logging along with it. Logging goes to a copy of stderr made beforehand instead.
+    if ARGS.eval_worker
+        && let Ok(stderr) = io::stderr().as_fd().try_clone_to_owned()
+    {
+        builder.target(Target::Pipe(Box::new(File::from(stderr))));
+    }
+    builder.init();
     // Now, we may begin logging.
     debug!("Logging is ready!");
 }
@@ -1,38 +1,743 @@
-use crate::args::ARGS;
-use git2::build::RepoBuilder;
-use git2::{FetchOptions, Repository};
-use log::info;
-use std::path::PathBuf;
-use std::sync::LazyLock;
+use crate::Error;
+use crate::args::{ARGS, DATA_PATH};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{
+    FetchOptions, Oid, Progress, Reference, RemoteCallbacks, Repository, WorktreeAddOptions,
+    WorktreePruneOptions,
+};
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io};
 use tempfile::env::temp_dir;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::Duration;
 
-pub(crate) static NIXPKGS_PATH: LazyLock<PathBuf> =
-    LazyLock::new(|| temp_dir().join(PathBuf::from("nixpkgs")));
-pub(crate) static NIXPKGS_REPO: LazyLock<Mutex<Option<Repository>>> =
-    LazyLock::new(|| Mutex::new(None));
+pub(crate) static NIXPKGS_PATH: LazyLock<PathBuf> = LazyLock::new(|| DATA_PATH.join("nixpkgs"));
+/// Anything reading from the channel checkouts holds this for reading, so only moving the
+/// checkouts has to wait for everyone else.
+pub(crate) static NIXPKGS_REPO: LazyLock<RwLock<Option<Arc<Nixpkgs>>>> =
+    LazyLock::new(|| RwLock::new(None));
+/// Every channel is checked out in here, as a worktree of the repository at `NIXPKGS_PATH`.
+pub(crate) static WORKTREES_PATH: LazyLock<PathBuf> =
+    LazyLock::new(|| DATA_PATH.join("nixpkgs-worktrees"));
+/// Where anything worked out from a nixpkgs revision is kept, so it survives restarts.
+pub(crate) static CACHE_PATH: LazyLock<PathBuf> = LazyLock::new(|| DATA_PATH.join("cache"));
+/// Worktrees checked out for `/eval`'s `rev`.
+static REVISION_CHECKOUTS: LazyLock<Checkouts> = LazyLock::new(|| Checkouts {
+    prefix: "rev-",
+    limit: 4,
+    recent: Mutex::new(Vec::new()),
+});
+/// Worktrees indexes are built in, at most one for each channel's revision. Each is a whole
+/// checkout of nixpkgs, so on top of the channels' own checkouts these can take as much disk
+/// space again.
+static PINNED_CHECKOUTS: LazyLock<Checkouts> = LazyLock::new(|| Checkouts {
+    prefix: "pin-",
+    limit: ARGS.channels.len(),
+    recent: Mutex::new(Vec::new()),
+});
+/// Bumped whenever the channel checkouts move, so anything derived from it can tell
+/// that it has gone stale.
+pub(crate) static NIXPKGS_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// What's being done to the repository right now, and how far along it is.
+static ACTIVITY: LazyLock<std::sync::Mutex<(Activity, TransferProgress)>> =
+    LazyLock::new(|| std::sync::Mutex::new((Activity::Idle, TransferProgress::default())));
 
-pub(crate) fn nixpkgs_repo() -> Repository {
+/// The nixpkgs repository. `Repository` can only be used from one thread at a time, so it has a
+/// lock of its own on top of `NIXPKGS_REPO`. Anything not touching the channel checkouts, like
+/// fetching, only needs this one.
+pub(crate) struct Nixpkgs(std::sync::Mutex<Repository>);
+
+impl Nixpkgs {
+    pub(crate) fn repository(&self) -> MutexGuard<'_, Repository> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) enum Activity {
+    Idle,
+    Cloning,
+    Fetching,
+    /// Setting nixpkgs up failed, and will be tried again at `retry_at`, in seconds since the
+    /// epoch.
+    Failed {
+        error: String,
+        retry_at: u64,
+    },
+}
+
+/// How far along the clone or fetch in progress is, as git reports it.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct TransferProgress {
+    pub(crate) received_objects: usize,
+    pub(crate) total_objects: usize,
+    pub(crate) indexed_deltas: usize,
+    pub(crate) total_deltas: usize,
+    pub(crate) received_bytes: usize,
+}
+
+impl From<Progress<'_>> for TransferProgress {
+    fn from(progress: Progress<'_>) -> Self {
+        TransferProgress {
+            received_objects: progress.received_objects(),
+            total_objects: progress.total_objects(),
+            indexed_deltas: progress.indexed_deltas(),
+            total_deltas: progress.total_deltas(),
+            received_bytes: progress.received_bytes(),
+        }
+    }
+}
+
+/// What's being done to the repository, and how far along any transfer is.
+pub(crate) fn activity() -> (Activity, TransferProgress) {
+    ACTIVITY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+fn set_activity(activity: Activity) {
+    *ACTIVITY.lock().unwrap_or_else(PoisonError::into_inner) =
+        (activity, TransferProgress::default());
+}
+
+/// Why nixpkgs can't be used right now, for commands to answer with.
+pub(crate) fn unavailable() -> Error {
+    match activity().0 {
+        Activity::Cloning => Error::from(
+            "Nixpkgs is still being cloned, which takes a few minutes. Try again later.",
+        ),
+        Activity::Failed { error, retry_at } => Error::from(format!(
+            "Setting up nixpkgs failed: {error}\nIt'll be tried again <t:{retry_at}:R>."
+        )),
+        Activity::Idle | Activity::Fetching => {
+            Error::from("Nixpkgs isn't checked out yet. Try again later.")
+        }
+    }
+}
+
+/// How long to wait before trying to set nixpkgs up again after the first failure. Each failure
+/// after that doubles it, up to `MAX_RETRY_DELAY`.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Opens nixpkgs, cloning it first if needed, makes it available to everything else and checks
+/// the channels out. Keeps trying until that works.
+pub(crate) async fn set_up_nixpkgs() {
+    migrate_from_temp_dir();
+    // Cloning takes minutes, which would otherwise stall everything else on this thread.
+    let repository = retrying("set up nixpkgs", || async {
+        tokio::task::block_in_place(nixpkgs_repo)
+    })
+    .await;
+    info!("Nixpkgs is ready at: {}", repository.path().display());
+    *NIXPKGS_REPO.write().await = Some(Arc::new(Nixpkgs(std::sync::Mutex::new(repository))));
+    // Nothing can be evaluated until the channels are checked out.
+    retrying("check out the nixpkgs channels", update_channels).await;
+}
+
+/// Runs `attempt` until it succeeds, waiting longer after each failure and reporting it as the
+/// current activity in the meantime.
+async fn retrying<T, F>(what: &str, mut attempt: impl FnMut() -> F) -> T
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    let mut delay = FIRST_RETRY_DELAY;
+    loop {
+        match attempt().await {
+            Ok(value) => {
+                set_activity(Activity::Idle);
+                return value;
+            }
+            Err(failure) => {
+                error!(
+                    "Failed to {what}, trying again in {} seconds: {failure}",
+                    delay.as_secs()
+                );
+                let retry_at = (SystemTime::now() + delay)
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                set_activity(Activity::Failed {
+                    error: failure.to_string(),
+                    retry_at,
+                });
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+/// Moves whatever older versions kept in the temporary directory over to `DATA_PATH`, so
+/// upgrading doesn't mean cloning nixpkgs all over again.
+fn migrate_from_temp_dir() {
+    let moves = [
+        (temp_dir().join("nixpkgs"), &*NIXPKGS_PATH),
+        (temp_dir().join("snix-bot-cache"), &*CACHE_PATH),
+    ];
+    for (old, new) in moves {
+        if !old.exists() || new.exists() || old == *new {
+            continue;
+        }
+        info!("Moving {} to {}.", old.display(), new.display());
+        let moved = fs::create_dir_all(&*DATA_PATH).and_then(|()| match fs::rename(&old, new) {
+            // The temporary directory is often a filesystem of its own.
+            Err(error) if error.kind() == io::ErrorKind::CrossesDevices => {
+                info!(
+                    "{} is on another filesystem, so it's being copied over instead.",
+                    old.display()
+                );
+                copy_dir(&old, new).and_then(|()| fs::remove_dir_all(&old))
+            }
+            renamed => renamed,
+        });
+        if let Err(error) = moved {
+            warn!(
+                "Couldn't move {} to {}, starting over instead: {error}",
+                old.display(),
+                new.display()
+            );
+            // Half a copy would be mistaken for the real thing.
+            if new.exists()
+                && let Err(error) = fs::remove_dir_all(new)
+            {
+                warn!("Couldn't remove {}: {error}", new.display());
+            }
+        }
+    }
+    // Worktrees remember where they are, so they're checked out again rather than moved.
+    let old_worktrees = temp_dir().join("nixpkgs-worktrees");
+    if !old_worktrees.exists() || old_worktrees == *WORKTREES_PATH {
+        return;
+    }
+    if let Err(error) = fs::remove_dir_all(&old_worktrees) {
+        warn!("Couldn't remove {}: {error}", old_worktrees.display());
+    }
+}
+
+/// Copies the directory `from` to `to` along with everything in it, for when it can't be moved.
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else if kind.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn nixpkgs_repo() -> Result<Repository, Error> {
     info!("Getting nixpkgs repo");
-    if let Ok(repository) = Repository::open(&*NIXPKGS_PATH) {
-        info!("nixpkgs is already cloned! Using that.");
-        repository
-    } else {
-        info!("nixpkgs was not already cloned, cloning it.");
-        clone_nixpkgs()
+    match Repository::open(&*NIXPKGS_PATH) {
+        Ok(repository) => match verify(&repository) {
+            Ok(()) => {
+                info!("nixpkgs is already cloned! Using that.");
+                return Ok(repository);
+            }
+            Err(problem) => {
+                warn!("The nixpkgs repo is broken, trying to repair it: {problem}");
+                match repair(&repository) {
+                    Ok(()) => return Ok(repository),
+                    Err(error) => {
+                        warn!("Couldn't repair the nixpkgs repo, cloning it again: {error}");
+                    }
+                }
+            }
+        },
+        Err(_) if NIXPKGS_PATH.exists() => {
+            warn!(
+                "{} isn't a repository nixpkgs can be used from, cloning it again.",
+                NIXPKGS_PATH.display()
+            );
+        }
+        Err(_) => info!("nixpkgs was not already cloned, cloning it."),
+    }
+    if NIXPKGS_PATH.exists() {
+        fs::remove_dir_all(&*NIXPKGS_PATH)?;
     }
+    clone_nixpkgs()
 }
 
-pub(crate) fn clone_nixpkgs() -> Repository {
+fn clone_nixpkgs() -> Result<Repository, Error> {
     info!("Starting nixpkgs clone!");
+    set_activity(Activity::Cloning);
+    // Nothing is ever checked out in the repository itself, only in the channel worktrees.
     RepoBuilder::new()
+        .bare(true)
         .fetch_options(clone_options())
         .clone(&ARGS.nixpkgs_url, &NIXPKGS_PATH)
-        .expect("Failed to clone nixpkgs!")
+        .map_err(|error| Error::from(format!("Cloning nixpkgs failed: {}", error.message())))
+}
+
+/// Checks that the repository survived whatever happened to it last. A clone or fetch that got
+/// cut short can leave it without an upstream, or with a HEAD pointing at objects it never got.
+fn verify(repository: &Repository) -> Result<(), Error> {
+    repository
+        .find_remote("origin")
+        .or(Err("It has no upstream remote."))?;
+    repository
+        .head()
+        .and_then(|head| head.peel_to_tree())
+        .or(Err("Its HEAD is missing or incomplete."))?;
+    Ok(())
+}
+
+/// Picks a broken repository back up by fetching the default channel into it again, which
+/// reuses whatever objects it already has instead of starting from scratch.
+fn repair(repository: &Repository) -> Result<(), Error> {
+    let channel = default_channel();
+    set_activity(Activity::Fetching);
+    let target = fetch_channel(repository, channel);
+    set_activity(Activity::Idle);
+    let branch = format!("refs/heads/{channel}");
+    repository.reference(&branch, target?, true, "Repair after a broken clone")?;
+    repository.set_head(&branch)?;
+    verify(repository)
+}
+
+/// The channel commands use when they aren't asked for a specific one.
+pub(crate) fn default_channel() -> &'static str {
+    ARGS.channels.first().map_or("master", String::as_str)
+}
+
+/// Where `channel` is checked out, or an error if it isn't one of the configured channels.
+/// Without a channel, that's the default channel's checkout.
+pub(crate) fn channel_checkout(channel: Option<&str>) -> Result<PathBuf, Error> {
+    let channel = channel.unwrap_or_else(default_channel);
+    if !ARGS.channels.iter().any(|configured| configured == channel) {
+        return Err(Error::from(format!(
+            "`{channel}` isn't a channel here. Try one of: {}",
+            ARGS.channels.join(", ")
+        )));
+    }
+    let checkout = WORKTREES_PATH.join(worktree_name(channel));
+    if !checkout.exists() {
+        return Err(unavailable());
+    }
+    Ok(checkout)
+}
+
+/// Branch names can hold all sorts of things that shouldn't end up in a path.
+fn worktree_name(channel: &str) -> String {
+    channel
+        .chars()
+        .map(|character| {
+            if character.is_ascii_alphanumeric() || character == '_' || character == '-' {
+                character
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Fetches the tip of every configured channel and moves its checkout there, returning the
+/// commit each channel ended up at.
+pub(crate) async fn update_channels() -> Result<Vec<(&'static str, Oid)>, Error> {
+    // Fetching doesn't touch the checkouts, so everything else carries on in the meantime.
+    let fetched = {
+        let nixpkgs = NIXPKGS_REPO.read().await;
+        let nixpkgs = nixpkgs.as_ref().ok_or_else(unavailable)?;
+        set_activity(Activity::Fetching);
+        let fetched = tokio::task::block_in_place(|| {
+            let repository = nixpkgs.repository();
+            ARGS.channels
+                .iter()
+                .map(|channel| Ok((channel.as_str(), fetch_channel(&repository, channel)?)))
+                .collect::<Result<Vec<(&'static str, Oid)>, Error>>()
+        });
+        set_activity(Activity::Idle);
+        fetched?
+    };
+
+    {
+        let nixpkgs = NIXPKGS_REPO.write().await;
+        let nixpkgs = nixpkgs.as_ref().ok_or_else(unavailable)?;
+        tokio::task::block_in_place(|| {
+            let repository = nixpkgs.repository();
+            for (channel, target) in &fetched {
+                info!("Moving the {channel} checkout to {target}.");
+                check_out(&repository, &worktree_name(channel), *target)?;
+            }
+            Ok::<_, Error>(())
+        })?;
+    }
+    NIXPKGS_GENERATION.fetch_add(1, Ordering::AcqRel);
+    Ok(fetched)
+}
+
+fn fetch_channel(repository: &Repository, channel: &str) -> Result<Oid, Error> {
+    info!("Fetching the {channel} channel.");
+    let remote_branch = format!("refs/remotes/origin/{channel}");
+    let mut remote = repository
+        .find_remote("origin")
+        .or(Err("Could not find the upstream remote!"))?;
+    remote
+        .fetch(
+            &[format!("+refs/heads/{channel}:{remote_branch}")],
+            Some(&mut clone_options()),
+            None,
+        )
+        .or(Err(format!("The {channel} branch is gone yo.")))?;
+    let target = repository
+        .find_reference(&remote_branch)
+        .ok()
+        .and_then(|reference| reference.target())
+        .ok_or("The commit I just fetched fucking *vanished*")?;
+    Ok(target)
+}
+
+/// Hard resets the worktree called `name` to `target`, adding the worktree if it's missing.
+fn check_out(repository: &Repository, name: &str, target: Oid) -> Result<(), Error> {
+    // git won't check the same branch out twice, so each worktree gets a branch of its own.
+    let branch = format!("refs/heads/snix-bot/{name}");
+    let reference = repository
+        .reference(&branch, target, true, "Reset to latest upstream")
+        .or(Err("Set target operation on the reflog shit itself..."))?;
+
+    match repository.find_worktree(name) {
+        Ok(worktree) if worktree.validate().is_ok() => {
+            let checkout = Repository::open_from_worktree(&worktree)
+                .or(Err(format!("The {name} worktree won't open.")))?;
+            // A checkout that got cut short leaves its index locked for good.
+            let index_lock = checkout.path().join("index.lock");
+            if index_lock.exists() {
+                warn!("Removing the stale index lock of the {name} worktree.");
+                fs::remove_file(index_lock)?;
+            }
+            checkout
+                .set_head(&branch)
+                .or(Err("Setting the new head shat itself."))?;
+            checkout
+                .checkout_head(Some(CheckoutBuilder::default().force()))
+                .or(Err("Moving the local checkout failed."))?;
+        }
+        stale => {
+            // A worktree whose directory went missing has to be forgotten before it can be
+            // added again, and a directory without a worktree is in the way.
+            if let Ok(worktree) = stale {
+                worktree
+                    .prune(None)
+                    .or(Err(format!("Couldn't prune the broken {name} worktree.")))?;
+            }
+            let path = WORKTREES_PATH.join(name);
+            if path.exists() {
+                fs::remove_dir_all(&path)?;
+            }
+            fs::create_dir_all(&*WORKTREES_PATH)?;
+            repository
+                .worktree(
+                    name,
+                    &path,
+                    Some(WorktreeAddOptions::new().reference(Some(&reference))),
+                )
+                .or(Err(format!("Couldn't check out the {name} worktree.")))?;
+        }
+    }
+    Ok(())
+}
+
+/// Revisions checked out for one purpose, kept apart from the others so they can't push each
+/// other's checkouts out.
+struct Checkouts {
+    /// What the names of the worktrees start with.
+    prefix: &'static str,
+    /// How many to keep checked out at once.
+    limit: usize,
+    /// The names of the worktrees, least recently used first. Each name is shared with whatever
+    /// is using the checkout, and isn't removed while it is.
+    recent: Mutex<Vec<Arc<str>>>,
+}
+
+/// A worktree checked out for some revision, which is kept checked out for as long as this is
+/// around.
+pub(crate) struct RevisionCheckout {
+    path: PathBuf,
+    _in_use: Arc<str>,
+}
+
+impl RevisionCheckout {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Checks out `revision` in a worktree of its own, fetching it first if needed. `revision` is a
+/// full commit hash, a branch, or `pr:` and a pull request number.
+pub(crate) async fn revision_checkout(revision: &str) -> Result<RevisionCheckout, Error> {
+    checkout_in(&REVISION_CHECKOUTS, revision).await
+}
+
+/// A checkout of the commit `revision`, which some channel is at. Unlike the channel's own
+/// checkout, it stays put when the channel is updated, so it's what evaluations too long to hold
+/// up updates for, like building indexes, run in.
+pub(crate) async fn pinned_checkout(revision: &str) -> Result<RevisionCheckout, Error> {
+    checkout_in(&PINNED_CHECKOUTS, revision).await
+}
+
+async fn checkout_in(
+    checkouts: &'static Checkouts,
+    revision: &str,
+) -> Result<RevisionCheckout, Error> {
+    // Holding `NIXPKGS_REPO` while fetching would hold up updates, and every evaluation queued
+    // behind them, for as long as the fetch takes.
+    let nixpkgs = NIXPKGS_REPO.read().await.clone().ok_or_else(unavailable)?;
+    let revision = revision.to_string();
+    // Fetching and checking out can take minutes, so they're kept off the async threads.
+    let target = tokio::task::spawn_blocking({
+        let nixpkgs = Arc::clone(&nixpkgs);
+        move || fetch_revision(&nixpkgs.repository(), &revision)
+    })
+    .await??;
+
+    let mut recent = checkouts.recent.lock().await;
+    let channels = NIXPKGS_REPO.read().await;
+    tokio::task::spawn_blocking(move || {
+        let _channels = channels;
+        check_out_revision(&nixpkgs.repository(), checkouts, &mut recent, target)
+    })
+    .await?
+}
+
+fn check_out_revision(
+    repository: &Repository,
+    checkouts: &Checkouts,
+    recent: &mut Vec<Arc<str>>,
+    target: Oid,
+) -> Result<RevisionCheckout, Error> {
+    // A commit's contents never change, so a checkout of it can be shared by every revision
+    // that points at it.
+    let name = format!("{}{target}", checkouts.prefix);
+    let known = recent.iter().position(|checkout| **checkout == *name);
+    if known.is_none()
+        || repository
+            .find_worktree(&name)
+            .and_then(|worktree| worktree.validate())
+            .is_err()
+    {
+        check_out(repository, &name, target)?;
+    }
+    let name = known.map_or_else(|| Arc::from(name), |index| recent.remove(index));
+    recent.push(Arc::clone(&name));
+
+    // Checkouts from before a restart aren't in `recent`, so they'd never be evicted otherwise.
+    let mut evicted: Vec<String> = repository
+        .worktrees()
+        .map(|worktrees| {
+            worktrees
+                .iter()
+                .flatten()
+                .filter(|worktree| {
+                    worktree.starts_with(checkouts.prefix)
+                        && !recent.iter().any(|checkout| &**checkout == *worktree)
+                })
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    // Checkouts still being evaluated in have their name shared, and stay until they aren't.
+    while recent.len() > checkouts.limit {
+        let Some(unused) = recent
+            .iter()
+            .position(|checkout| Arc::strong_count(checkout) == 1)
+        else {
+            break;
+        };
+        evicted.push(recent.remove(unused).to_string());
+    }
+    for evicted in evicted {
+        if let Err(error) = remove_checkout(repository, &evicted) {
+            warn!("Couldn't remove the {evicted} worktree: {error}");
+        }
+    }
+    Ok(RevisionCheckout {
+        path: WORKTREES_PATH.join(&*name),
+        _in_use: name,
+    })
+}
+
+/// Where to get a revision from.
+#[derive(Debug, PartialEq, Eq)]
+enum RevisionSource {
+    /// A full commit hash or a ref, which can be fetched from nixpkgs.
+    Remote(String),
+    /// An abbreviated commit hash, which can only be looked up among the commits already here.
+    Local,
+}
+
+fn revision_source(revision: &str) -> Result<RevisionSource, Error> {
+    let is_hash = |revision: &str| {
+        revision
+            .chars()
+            .all(|character| character.is_ascii_hexdigit())
+    };
+    if let Some(number) = revision.strip_prefix("pr:") {
+        if number.is_empty() || !number.chars().all(|character| character.is_ascii_digit()) {
+            return Err(Error::from(format!(
+                "`{number}` isn't a pull request number."
+            )));
+        }
+        Ok(RevisionSource::Remote(format!("refs/pull/{number}/head")))
+    } else if revision.len() == 40 && is_hash(revision) {
+        Ok(RevisionSource::Remote(revision.to_string()))
+    } else if revision.len() >= 7 && is_hash(revision) {
+        Ok(RevisionSource::Local)
+    } else if Reference::is_valid_name(&format!("refs/heads/{revision}")) {
+        Ok(RevisionSource::Remote(format!("refs/heads/{revision}")))
+    } else {
+        Err(Error::from(format!(
+            "`{revision}` isn't a commit hash, branch, or `pr:` and a pull request number."
+        )))
+    }
+}
+
+fn fetch_revision(repository: &Repository, revision: &str) -> Result<Oid, Error> {
+    let source = match revision_source(revision)? {
+        RevisionSource::Remote(source) => source,
+        // Only full hashes can be fetched, but an abbreviated one might already be here.
+        RevisionSource::Local => {
+            return repository
+                .revparse_single(revision)
+                .and_then(|object| object.peel_to_commit())
+                .map(|commit| commit.id())
+                .or(Err(Error::from(
+                    "I don't have that commit yet, so I'll need its full 40 character hash.",
+                )));
+        }
+    };
+    // A commit that's already here doesn't need fetching again.
+    if let Ok(commit) = Oid::from_str(&source).and_then(|oid| repository.find_commit(oid)) {
+        return Ok(commit.id());
+    }
+
+    info!("Fetching {source} from nixpkgs.");
+    let fetched = "refs/snix-bot/fetched";
+    let mut remote = repository
+        .find_remote("origin")
+        .or(Err("Could not find the upstream remote!"))?;
+    remote
+        .fetch(
+            &[format!("+{source}:{fetched}")],
+            Some(&mut clone_options()),
+            None,
+        )
+        .or(Err(format!("Couldn't fetch `{revision}` from nixpkgs.")))?;
+    let target = repository
+        .find_reference(fetched)
+        .ok()
+        .and_then(|reference| reference.peel_to_commit().ok())
+        .map(|commit| commit.id())
+        .ok_or("The commit I just fetched fucking *vanished*")?;
+    Ok(target)
+}
+
+fn remove_checkout(repository: &Repository, name: &str) -> Result<(), Error> {
+    if let Ok(worktree) = repository.find_worktree(name) {
+        worktree.prune(Some(
+            WorktreePruneOptions::new().valid(true).working_tree(true),
+        ))?;
+    }
+    if let Ok(mut branch) = repository.find_reference(&format!("refs/heads/snix-bot/{name}")) {
+        branch.delete()?;
+    }
+    Ok(())
+}
+
+/// The commit `channel` has checked out, if it has been checked out yet.
+pub(crate) fn nixpkgs_revision(channel: &str) -> Option<String> {
+    channel_commit(channel).map(|(revision, _)| revision)
+}
+
+/// The commit `channel` has checked out along with when it was committed, in seconds since the
+/// epoch. The checkout is opened on its own so this never has to wait on the repository.
+pub(crate) fn channel_commit(channel: &str) -> Option<(String, i64)> {
+    let checkout = Repository::open(WORKTREES_PATH.join(worktree_name(channel))).ok()?;
+    let commit = checkout.head().ok()?.peel_to_commit().ok()?;
+    Some((commit.id().to_string(), commit.time().seconds()))
 }
 
 fn clone_options() -> FetchOptions<'static> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.transfer_progress(|progress| {
+        ACTIVITY.lock().unwrap_or_else(PoisonError::into_inner).1 = progress.into();
+        true
+    });
     let mut clone_config: FetchOptions = FetchOptions::new();
     clone_config.depth(ARGS.clone_depth);
+    clone_config.remote_callbacks(callbacks);
     clone_config
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(source: &str) -> Option<RevisionSource> {
+        Some(RevisionSource::Remote(source.to_string()))
+    }
+
+    #[test]
+    fn pull_requests_are_fetched_by_number() {
+        assert_eq!(
+            revision_source("pr:12345").ok(),
+            remote("refs/pull/12345/head")
+        );
+        assert!(revision_source("pr:").is_err());
+        assert!(revision_source("pr:12a").is_err());
+        assert!(revision_source("pr:-1").is_err());
+    }
+
+    #[test]
+    fn full_hashes_are_fetched_and_short_ones_looked_up() {
+        let full = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(revision_source(full).ok(), remote(full));
+        assert_eq!(revision_source("0123456").ok(), Some(RevisionSource::Local));
+        assert_eq!(
+            revision_source(&full[..39]).ok(),
+            Some(RevisionSource::Local)
+        );
+        // Too short to be a hash, so it can only be a branch.
+        assert_eq!(revision_source("abc123").ok(), remote("refs/heads/abc123"));
+    }
+
+    #[test]
+    fn anything_else_has_to_be_a_valid_branch() {
+        assert_eq!(
+            revision_source("nixos-unstable").ok(),
+            remote("refs/heads/nixos-unstable")
+        );
+        assert_eq!(
+            revision_source("release-24.05").ok(),
+            remote("refs/heads/release-24.05")
+        );
+        assert!(revision_source("two words").is_err());
+        assert!(revision_source("double..dot").is_err());
+        assert!(revision_source("").is_err());
+    }
+
+    #[test]
+    fn plain_channel_names_are_kept() {
+        assert_eq!(worktree_name("master"), "master");
+        assert_eq!(worktree_name("nixos-unstable"), "nixos-unstable");
+        assert_eq!(worktree_name("release_24"), "release_24");
+    }
+
+    #[test]
+    fn anything_else_becomes_a_dash() {
+        assert_eq!(worktree_name("nixos-24.05"), "nixos-24-05");
+        assert_eq!(worktree_name("feature/thing"), "feature-thing");
+        assert_eq!(worktree_name("../../etc"), "------etc");
+        assert_eq!(worktree_name("ünicode"), "-nicode");
+    }
+}